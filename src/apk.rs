@@ -0,0 +1,105 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reading of native libraries embedded in Android APKs. Android can load a
+//! library directly from an APK if it is stored uncompressed, in which case
+//! stack frames refer to it either as `base.apk!/lib/arm64-v8a/libxul.so`, or
+//! as `base.apk` with an offset into the APK rather than into the library.
+
+use crate::local;
+use anyhow::Result;
+use std::fs::File;
+use std::io::Read;
+use symbolic_common::DebugId;
+use zip::{CompressionMethod, ZipArchive};
+
+/// A stored (uncompressed) entry in an APK.
+pub struct ApkEntry {
+    pub name: String,
+
+    /// The offset of the entry's data within the APK.
+    pub data_start: u64,
+
+    pub size: u64,
+}
+
+/// Split a path of the form `<apk>!/<entry>` into its two parts.
+pub fn split_path(file_name: &str) -> Option<(&str, &str)> {
+    let (apk_file, entry_name) = file_name.split_once("!/")?;
+    if apk_file.is_empty() || entry_name.is_empty() {
+        return None;
+    }
+    Some((apk_file, entry_name))
+}
+
+/// Is this the path of an APK? This doesn't check the file contents.
+pub fn is_apk(file_name: &str) -> bool {
+    file_name.ends_with(".apk")
+}
+
+fn open(apk_file: &str) -> Result<ZipArchive<File>> {
+    Ok(ZipArchive::new(File::open(apk_file)?)?)
+}
+
+/// Get the stored entries within an APK, sorted by offset.
+pub fn read_entries(apk_file: &str) -> Result<Vec<ApkEntry>> {
+    let mut archive = open(apk_file)?;
+    let mut entries = vec![];
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.compression() == CompressionMethod::Stored && entry.is_file() {
+            entries.push(ApkEntry {
+                name: entry.name().to_string(),
+                data_start: entry.data_start(),
+                size: entry.size(),
+            });
+        }
+    }
+    entries.sort_unstable_by_key(|entry| entry.data_start);
+    Ok(entries)
+}
+
+/// Find the stored entry containing `offset`.
+pub fn entry_at_offset(entries: &[ApkEntry], offset: u64) -> Option<&ApkEntry> {
+    let index = match entries.binary_search_by_key(&offset, |entry| entry.data_start) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(next_index) => next_index - 1,
+    };
+    let entry = &entries[index];
+    if offset - entry.data_start < entry.size {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Read the contents of an entry within an APK.
+pub fn read_entry(apk_file: &str, entry_name: &str) -> Result<Vec<u8>> {
+    let mut archive = open(apk_file)?;
+    let mut entry = archive.by_name(entry_name)?;
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Get the code ID and debug ID of a stored ELF entry within an APK. Only
+/// the entry's ELF header and notes are read, not the whole entry. Returns
+/// `None` for compressed entries, which can't be loaded directly anyway.
+pub fn read_entry_ids(apk_file: &str, entry_name: &str) -> Option<(String, DebugId)> {
+    let data_start = entry_data_start(apk_file, entry_name)?;
+    local::read_elf_ids(&mut File::open(apk_file).ok()?, data_start)
+}
+
+/// Get the offset of a stored entry's data within an APK, so the entry can
+/// be read in place.
+pub fn entry_data_start(apk_file: &str, entry_name: &str) -> Option<u64> {
+    let mut archive = open(apk_file).ok()?;
+    let entry = archive.by_name(entry_name).ok()?;
+    if entry.compression() != CompressionMethod::Stored {
+        return None;
+    }
+    Some(entry.data_start())
+}
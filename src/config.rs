@@ -0,0 +1,206 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The config file given by `--config`, which is TOML and can declare output
+//! templates for each resolution level, and extra frame grammars, each with
+//! optional output templates of its own:
+//!
+//! ```text
+//! [template]
+//! fixed = '{prefix}{function} ({basename}:{line}){suffix}'
+//!
+//! [[frame]]
+//! pattern = '^(?P<prefix>\s*)(?P<frameno>\d+)\s+(?P<module>\S+)\s+0x(?P<offset>[0-9a-f]+)(?P<suffix>.*)$'
+//! template = '{prefix}{frameno} {function} at {location}{suffix}'
+//! template_unresolved = '{prefix}{frameno} {module} + {offset}{suffix}'
+//! ```
+//!
+//! The frame grammars are tried in order, before the built-in one for lines
+//! produced by MozFormatCodeAddress().
+//!
+//! It can also replace the lists of frames that `--signature` skips or keeps
+//! as a prefix, each of which is a list of regexes:
+//!
+//! ```text
+//! [signature]
+//! irrelevant = ['abort', 'mozalloc_abort', 'MOZ_CrashPrintf']
+//! prefix = ['moz_xmalloc', 'nsTArray_base<\.\.\.>::.*']
+//! ```
+//!
+//! And it can add version control schemes, in the form taken by
+//! `--vcs-scheme`:
+//!
+//! ```text
+//! [vcs]
+//! schemes = ['svn', 'gcs:none']
+//! ```
+
+use crate::signature::{self, SignatureInfo};
+use crate::template::{Template, Templates, FIELDS};
+use crate::vcs::VcsScheme;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::fs;
+
+/// The captures a frame pattern may have. A pattern must have either
+/// `module` and `offset` (a hex offset within the module) or `address` (a
+/// hex absolute address, which can only be resolved with `--jit-map`).
+pub const CAPTURES: &[&str] = &[
+    "prefix", "frameno", "func", "module", "offset", "address", "suffix",
+];
+
+pub struct FramePattern {
+    pub re: Regex,
+
+    /// The template for all resolution levels, if given.
+    pub template: Option<Template>,
+
+    /// The templates for particular resolution levels, which take
+    /// precedence over `template`.
+    pub templates: Templates,
+}
+
+#[derive(Default)]
+pub struct Config {
+    /// The templates for each resolution level, for frame patterns without
+    /// templates of their own.
+    pub templates: Templates,
+
+    pub frame_patterns: Vec<FramePattern>,
+
+    /// The frame lists used by `--signature`.
+    pub signature: SignatureInfo,
+
+    /// Version control schemes in addition to the built-in ones.
+    pub vcs_schemes: Vec<VcsScheme>,
+}
+
+impl FramePattern {
+    fn new(pattern: &str) -> Result<FramePattern> {
+        let re = Regex::new(pattern).context("parse frame pattern in")?;
+        let names: Vec<_> = re.capture_names().flatten().collect();
+        if let Some(name) = names.iter().find(|name| !CAPTURES.contains(name)) {
+            bail!(
+                "parse frame pattern `{}` (unknown capture `{}`) in",
+                pattern,
+                name
+            );
+        }
+        let has = |name| names.contains(&name);
+        if !(has("address") || (has("module") && has("offset"))) {
+            bail!(
+                "parse frame pattern `{}` (it needs `module` and `offset` captures, or an \
+                 `address` capture) in",
+                pattern
+            );
+        }
+        Ok(FramePattern {
+            re,
+            template: None,
+            templates: Templates::default(),
+        })
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config> {
+        let value: toml::Value = text.parse().context("parse")?;
+        let mut config = Config::default();
+        for (key, value) in value.as_table().context("parse")? {
+            match key.as_str() {
+                "template" => {
+                    let templates = value.as_table().context("read `template` in")?;
+                    for (level, template) in templates {
+                        let template = template
+                            .as_str()
+                            .with_context(|| format!("read `template.{}` in", level))?;
+                        config
+                            .templates
+                            .set(level, template)
+                            .map_err(anyhow::Error::msg)
+                            .context("parse template in")?;
+                    }
+                }
+                "frame" => {
+                    let frames = value.as_array().context("read `frame` in")?;
+                    for frame in frames {
+                        config.frame_patterns.push(Config::parse_frame(frame)?);
+                    }
+                }
+                "signature" => {
+                    let lists = value.as_table().context("read `signature` in")?;
+                    for (key, list) in lists {
+                        let patterns: Vec<_> = list
+                            .as_array()
+                            .and_then(|list| list.iter().map(toml::Value::as_str).collect())
+                            .with_context(|| format!("read `signature.{}` in", key))?;
+                        let patterns =
+                            signature::compile(&patterns).context("parse signature pattern in")?;
+                        match key.as_str() {
+                            "irrelevant" => config.signature.irrelevant = patterns,
+                            "prefix" => config.signature.prefix = patterns,
+                            _ => bail!("parse (unknown key `signature.{}`)", key),
+                        }
+                    }
+                }
+                "vcs" => {
+                    let vcs = value.as_table().context("read `vcs` in")?;
+                    for (key, specs) in vcs {
+                        if key != "schemes" {
+                            bail!("parse (unknown key `vcs.{}`)", key);
+                        }
+                        let specs: Vec<_> = specs
+                            .as_array()
+                            .and_then(|specs| specs.iter().map(toml::Value::as_str).collect())
+                            .context("read `vcs.schemes` in")?;
+                        for spec in specs {
+                            let scheme = VcsScheme::from_spec(spec)
+                                .map_err(|msg| {
+                                    anyhow::Error::msg(msg.trim_end_matches('.').to_string())
+                                })
+                                .context("parse VCS scheme in")?;
+                            config.vcs_schemes.push(scheme);
+                        }
+                    }
+                }
+                _ => bail!("parse (unknown key `{}`)", key),
+            }
+        }
+        Ok(config)
+    }
+
+    fn parse_frame(frame: &toml::Value) -> Result<FramePattern> {
+        let frame = frame.as_table().context("read `frame` in")?;
+        let pattern = frame
+            .get("pattern")
+            .context("find `frame.pattern` in")?
+            .as_str()
+            .context("read `frame.pattern` in")?;
+        let mut frame_pattern = FramePattern::new(pattern)?;
+        for (key, value) in frame {
+            let value = value
+                .as_str()
+                .with_context(|| format!("read `frame.{}` in", key))?;
+            let result = match key.as_str() {
+                "pattern" => continue,
+                "template" => Template::parse(value, FIELDS)
+                    .map(|template| frame_pattern.template = Some(template)),
+                _ => match key.strip_prefix("template_") {
+                    Some(level) => frame_pattern.templates.set(level, value),
+                    None => bail!("parse (unknown key `frame.{}`)", key),
+                },
+            };
+            result
+                .map_err(anyhow::Error::msg)
+                .context("parse frame template in")?;
+        }
+        Ok(frame_pattern)
+    }
+}
+
+pub fn read_config(file: &str) -> Result<Config> {
+    let text = fs::read_to_string(file).context("read")?;
+    Config::parse(&text)
+}
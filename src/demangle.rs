@@ -0,0 +1,213 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! How function names are printed, as chosen by `--demangle` and
+//! `--strip-rust-hash`. Names may be mangled, or already demangled, as in
+//! Breakpad symbol files, so parameters and templates are also removed
+//! textually where needed.
+
+use symbolic_common::{Language, Name, NameMangling};
+use symbolic_demangle::{Demangle, DemangleOptions};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Style {
+    /// The complete demangled name, e.g. `Foo<int>::bar(char const*)`.
+    #[default]
+    Full,
+
+    /// The name without parameters or return type, e.g. `Foo<int>::bar`.
+    NameOnly,
+
+    /// The name without parameters or return type, and with the contents of
+    /// template argument lists removed, e.g. `Foo<...>::bar`.
+    Collapsed,
+
+    /// The complete demangled name followed by the mangled name, if they
+    /// differ, e.g. `Foo<int>::bar(char const*) [_ZN3FooIiE3barEPKc]`.
+    Mangled,
+}
+
+impl Style {
+    /// Parse an argument to `--demangle`.
+    pub fn parse(spec: &str) -> Result<Style, String> {
+        match spec {
+            "full" => Ok(Style::Full),
+            "name-only" => Ok(Style::NameOnly),
+            "collapsed" => Ok(Style::Collapsed),
+            "mangled" => Ok(Style::Mangled),
+            _ => Err(format!("bad `--demangle` style `{}`.", spec)),
+        }
+    }
+}
+
+/// The options given by `--demangle` and `--strip-rust-hash`.
+#[derive(Clone, Copy, Default)]
+pub struct NameOptions {
+    pub style: Style,
+    pub strip_rust_hash: bool,
+}
+
+impl NameOptions {
+    /// The name of a function as it should be printed.
+    pub fn display_name(&self, mangled_name: &str) -> String {
+        format_name(mangled_name, self.style, self.strip_rust_hash)
+    }
+
+    /// The name of a function without parameters or return type, whatever
+    /// the style.
+    pub fn short_name(&self, mangled_name: &str) -> String {
+        format_name(mangled_name, Style::NameOnly, self.strip_rust_hash)
+    }
+}
+
+fn demangle(mangled_name: &str, options: DemangleOptions) -> String {
+    Name::new(mangled_name, NameMangling::Mangled, Language::Unknown)
+        .try_demangle(options)
+        .to_string()
+}
+
+/// Remove the hash from a legacy Rust symbol, e.g. the `::h0123456789abcdef`
+/// in `core::panicking::panic::h0123456789abcdef`. (Rust symbols that are
+/// still mangled are demangled without it anyway.)
+pub fn strip_rust_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((rest, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            rest
+        }
+        _ => name,
+    }
+}
+
+/// Remove the qualifiers at the end of a demangled name, e.g. the ` const`
+/// in `Foo::bar(int) const`.
+fn strip_qualifiers(name: &str) -> &str {
+    let mut name = name.trim_end();
+    for qualifier in [" const", " volatile", " &&", " &", " noexcept"].iter() {
+        name = name.strip_suffix(qualifier).unwrap_or(name);
+    }
+    name
+}
+
+/// Remove the parameter list and any qualifiers after it from an already
+/// demangled name, e.g. `Foo::bar(int) const` becomes `Foo::bar`.
+pub fn strip_parameters(name: &str) -> &str {
+    let name = strip_qualifiers(name);
+    if !name.ends_with(')') {
+        return name;
+    }
+    let mut depth = 0;
+    for (i, c) in name.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    let before = &name[..i];
+                    // An empty name means the whole thing is parenthesized,
+                    // e.g. `(anonymous namespace)`, and `operator()` without
+                    // parameters is just a name. Neither has a parameter
+                    // list to remove.
+                    return if before.is_empty() || before.ends_with("operator") {
+                        name
+                    } else {
+                        before
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    name
+}
+
+/// Replace the contents of each outermost template argument list with
+/// `...`, e.g. `mozilla::Maybe<nsTArray<int>>::emplace` becomes
+/// `mozilla::Maybe<...>::emplace`. The `<` and `>` in operator names such as
+/// `operator<<` and `operator->` are kept.
+pub fn collapse_templates(name: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        // Copy operator names, and arrows, verbatim. `operator` must be a
+        // whole identifier, not part of one like `my_operator`.
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        if depth == 0 && !out.ends_with(is_ident) {
+            if let Some(after) = rest
+                .strip_prefix("operator")
+                .filter(|after| !after.starts_with(is_ident))
+            {
+                let symbols = after
+                    .find(|c: char| !"<>=-".contains(c))
+                    .unwrap_or(after.len());
+                let len = "operator".len() + symbols;
+                out.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        if rest.starts_with("->") {
+            if depth == 0 {
+                out.push_str("->");
+            }
+            rest = &rest[2..];
+            continue;
+        }
+        match c {
+            '<' => {
+                if depth == 0 {
+                    out.push_str("<...");
+                }
+                depth += 1;
+            }
+            '>' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    out.push('>');
+                }
+            }
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// The name of a function in `style`.
+pub fn format_name(mangled_name: &str, style: Style, strip_hash: bool) -> String {
+    let strip = |name: &str| {
+        if strip_hash {
+            strip_rust_hash(name).to_string()
+        } else {
+            name.to_string()
+        }
+    };
+    match style {
+        Style::Full => strip(&demangle(mangled_name, DemangleOptions::complete())),
+        Style::NameOnly => {
+            // Names that were mangled are demangled without parameters,
+            // though maybe with qualifiers, but already demangled names need
+            // their parameters removed.
+            let name = demangle(mangled_name, DemangleOptions::name_only());
+            if name == mangled_name {
+                strip(strip_parameters(&name))
+            } else {
+                strip(strip_qualifiers(&name))
+            }
+        }
+        Style::Collapsed => {
+            collapse_templates(&format_name(mangled_name, Style::NameOnly, strip_hash))
+        }
+        Style::Mangled => {
+            let name = strip(&demangle(mangled_name, DemangleOptions::complete()));
+            if name == mangled_name {
+                name
+            } else {
+                format!("{} [{}]", name, mangled_name)
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Diagnostics printed to stderr: warnings and errors (unless `--quiet` is
+//! given), details of each file loaded (if `--verbose` is given), and the
+//! statistics printed at exit by `--summary` and checked by `--fail-on`.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// How much is printed, as chosen by `--quiet` and `--verbose`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+/// Print a warning, unless in quiet mode.
+pub fn warning(level: Level, msg: impl Display) {
+    if level >= Level::Normal {
+        eprintln!("fix-stacks: warning: {}", msg);
+    }
+}
+
+/// Print an error that isn't fatal, unless in quiet mode.
+pub fn error(level: Level, msg: impl Display) {
+    if level >= Level::Normal {
+        eprintln!("fix-stacks: error: {}", msg);
+    }
+}
+
+/// Print an error about `file`, with its causes, unless in quiet mode.
+pub fn file_error(level: Level, err: &anyhow::Error, file: &str) {
+    if level >= Level::Normal {
+        eprintln!("fix-stacks: error: failed to {} `{}`", err, file);
+        err.chain()
+            .skip(1)
+            .for_each(|cause| eprintln!("fix-stacks: {}", cause));
+    }
+}
+
+/// Print a message in verbose mode.
+pub fn verbose(level: Level, msg: impl Display) {
+    if level >= Level::Verbose {
+        eprintln!("fix-stacks: {}", msg);
+    }
+}
+
+/// How much of a frame was resolved.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resolution {
+    /// The function name and source location were found.
+    Fixed,
+
+    /// Only the function name was found, e.g. from a symbol table.
+    NameOnly,
+
+    /// Nothing was found.
+    Unresolved,
+}
+
+/// Statistics about the frames seen in the input.
+#[derive(Default)]
+pub struct Stats {
+    pub frames: u64,
+    pub fixed: u64,
+    pub name_only: u64,
+    pub unresolved: u64,
+
+    /// The modules that couldn't be loaded, with the reason for each.
+    pub failures: BTreeMap<String, String>,
+
+    /// The number of files that were ignored because their build ID didn't
+    /// match, which usually means they are stale.
+    pub mismatches: u64,
+}
+
+impl Stats {
+    pub fn record(&mut self, resolution: Resolution) {
+        self.frames += 1;
+        match resolution {
+            Resolution::Fixed => self.fixed += 1,
+            Resolution::NameOnly => self.name_only += 1,
+            Resolution::Unresolved => self.unresolved += 1,
+        }
+    }
+
+    pub fn record_failure(&mut self, module: &str, reason: String) {
+        self.failures.entry(module.to_string()).or_insert(reason);
+    }
+
+    /// Print the summary. This is done even in quiet mode, because it was
+    /// asked for.
+    pub fn print_summary(&self) {
+        eprintln!(
+            "fix-stacks: summary: {} frames: {} fixed, {} name only, {} unresolved",
+            self.frames, self.fixed, self.name_only, self.unresolved
+        );
+        if !self.failures.is_empty() {
+            eprintln!(
+                "fix-stacks: summary: {} module(s) failed to load:",
+                self.failures.len()
+            );
+            for (module, reason) in &self.failures {
+                eprintln!("fix-stacks:   `{}`: {}", module, reason);
+            }
+        }
+        if self.mismatches > 0 {
+            eprintln!(
+                "fix-stacks: summary: {} file(s) ignored due to build ID mismatches",
+                self.mismatches
+            );
+        }
+    }
+
+    /// The percentage of frames for which at least the function was found.
+    fn resolved_percentage(&self) -> f64 {
+        if self.frames == 0 {
+            return 100.0;
+        }
+        (self.fixed + self.name_only) as f64 * 100.0 / self.frames as f64
+    }
+
+    /// Get the exit status for the first of the `--fail-on` conditions that
+    /// holds, in order of exit status, printing the reason at `level`.
+    /// Returns 0 if none of them do.
+    pub fn exit_status(&self, fail_on: &[FailOn], level: Level) -> i32 {
+        let mut fail_on = fail_on.to_vec();
+        fail_on.sort_by_key(FailOn::exit_status);
+        for condition in fail_on {
+            let reason = match condition {
+                FailOn::LoadError if !self.failures.is_empty() => {
+                    format!("{} module(s) failed to load", self.failures.len())
+                }
+                FailOn::Mismatch if self.mismatches > 0 => format!(
+                    "{} file(s) ignored due to build ID mismatches",
+                    self.mismatches
+                ),
+                FailOn::Unresolved if self.unresolved > 0 => {
+                    format!("{} frame(s) unresolved", self.unresolved)
+                }
+                FailOn::ResolvedBelow(percentage) if self.resolved_percentage() < percentage => {
+                    format!(
+                        "only {:.1}% of frames resolved, below {}%",
+                        self.resolved_percentage(),
+                        percentage
+                    )
+                }
+                _ => continue,
+            };
+            error(level, format!("{} (--fail-on {})", reason, condition));
+            return condition.exit_status();
+        }
+        0
+    }
+}
+
+/// A condition under which to exit with a nonzero status, given by
+/// `--fail-on` or `--strict`. Each has its own exit status, which is 1 for
+/// other errors.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FailOn {
+    /// A module failed to load.
+    LoadError,
+
+    /// A file was ignored because its build ID didn't match.
+    Mismatch,
+
+    /// A frame was unresolved.
+    Unresolved,
+
+    /// Fewer than this percentage of frames had at least their function
+    /// resolved.
+    ResolvedBelow(f64),
+}
+
+impl FailOn {
+    /// The conditions used by `--strict`.
+    pub const STRICT: [FailOn; 3] = [FailOn::LoadError, FailOn::Mismatch, FailOn::Unresolved];
+
+    /// Parse an argument to `--fail-on`, e.g. `unresolved` or
+    /// `resolved-below=90`.
+    pub fn parse(spec: &str) -> Result<FailOn, String> {
+        let fail_on = match spec.split_once('=') {
+            None if spec == "load-error" => FailOn::LoadError,
+            None if spec == "mismatch" => FailOn::Mismatch,
+            None if spec == "unresolved" => FailOn::Unresolved,
+            Some(("resolved-below", percentage)) => match percentage.parse() {
+                Ok(percentage) if (0.0..=100.0).contains(&percentage) => {
+                    FailOn::ResolvedBelow(percentage)
+                }
+                _ => return Err(format!("bad percentage `{}`.", percentage)),
+            },
+            _ => return Err(format!("bad `--fail-on` condition `{}`.", spec)),
+        };
+        Ok(fail_on)
+    }
+
+    fn exit_status(&self) -> i32 {
+        match self {
+            FailOn::LoadError => 2,
+            FailOn::Mismatch => 3,
+            FailOn::Unresolved => 4,
+            FailOn::ResolvedBelow(_) => 5,
+        }
+    }
+}
+
+impl Display for FailOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailOn::LoadError => write!(f, "load-error"),
+            FailOn::Mismatch => write!(f, "mismatch"),
+            FailOn::Unresolved => write!(f, "unresolved"),
+            FailOn::ResolvedBelow(percentage) => write!(f, "resolved-below={}", percentage),
+        }
+    }
+}
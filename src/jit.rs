@@ -0,0 +1,198 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reading of the files that JITs such as SpiderMonkey's write to describe the
+//! code they generate: `perf-<pid>.map` files, whose lines have the form
+//! `<start> <size> <name>` (with hex numbers), and jitdump files, as specified
+//! in the Linux kernel's `tools/perf/Documentation/jitdump-specification.txt`.
+//! All addresses are absolute.
+
+use anyhow::{bail, Context, Result};
+use fxhash::FxHashMap;
+use std::convert::TryInto;
+use std::fs;
+
+/// A line within JIT code.
+pub struct JitLine {
+    pub address: u64,
+    pub line: u32,
+
+    /// The column, or 0 if it's unknown.
+    pub column: u32,
+
+    pub file: String,
+}
+
+/// A JIT-compiled function.
+pub struct JitFunc {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+
+    /// Sorted by `address`.
+    pub lines: Vec<JitLine>,
+}
+
+const JITDUMP_MAGIC: u32 = 0x4a695444;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+/// Read a perf map or jitdump file.
+pub fn read_jit_file(path: &str) -> Result<Vec<JitFunc>> {
+    let data = fs::read(path).context("read")?;
+    if data.len() >= 4 && u32::from_le_bytes(data[..4].try_into().unwrap()) == JITDUMP_MAGIC {
+        read_jitdump(&data).context("parse jitdump file")
+    } else {
+        let text = String::from_utf8(data).context("parse perf map file")?;
+        read_perf_map(&text).context("parse perf map file")
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn read_perf_map(text: &str) -> Result<Vec<JitFunc>> {
+    let mut funcs = vec![];
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let address = parts.next().and_then(parse_hex);
+        let size = parts.next().and_then(parse_hex);
+        match (address, size, parts.next()) {
+            (Some(address), Some(size), Some(name)) => funcs.push(JitFunc {
+                address,
+                size,
+                name: name.to_string(),
+                lines: vec![],
+            }),
+            _ => bail!("bad line {}", index + 1),
+        }
+    }
+    Ok(funcs)
+}
+
+/// A cursor for reading little-endian jitdump data.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("unexpected end of data");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = match self.data.iter().position(|&b| b == 0) {
+            Some(len) => len,
+            None => bail!("unterminated string"),
+        };
+        let string = self.bytes(len + 1)?;
+        Ok(&string[..len])
+    }
+}
+
+fn read_jitdump(data: &[u8]) -> Result<Vec<JitFunc>> {
+    let mut header = Reader { data };
+    let _magic = header.u32()?;
+    let _version = header.u32()?;
+    let header_size = header.u32()? as usize;
+    let mut reader = Reader {
+        data: data.get(header_size..).context("bad header size")?,
+    };
+
+    let mut funcs: Vec<JitFunc> = vec![];
+    let mut debug_infos: FxHashMap<u64, Vec<JitLine>> = FxHashMap::default();
+    while !reader.data.is_empty() {
+        let id = reader.u32()?;
+        let record_size = reader.u32()? as usize;
+        let _timestamp = reader.u64()?;
+        let mut record = Reader {
+            data: reader.bytes(record_size.checked_sub(16).context("bad record size")?)?,
+        };
+        match id {
+            JIT_CODE_LOAD => {
+                let _pid = record.u32()?;
+                let _tid = record.u32()?;
+                let _vma = record.u64()?;
+                let address = record.u64()?;
+                let size = record.u64()?;
+                let _code_index = record.u64()?;
+                let name = String::from_utf8_lossy(record.string()?).into_owned();
+                // A debug info record precedes the load record it applies to.
+                let mut lines = debug_infos.remove(&address).unwrap_or_default();
+                lines.sort_by_key(|line| line.address);
+                funcs.push(JitFunc {
+                    address,
+                    size,
+                    name,
+                    lines,
+                });
+            }
+            JIT_CODE_MOVE => {
+                let _pid = record.u32()?;
+                let _tid = record.u32()?;
+                let _vma = record.u64()?;
+                let old_address = record.u64()?;
+                let new_address = record.u64()?;
+                for func in funcs.iter_mut().filter(|func| func.address == old_address) {
+                    func.address = new_address;
+                    for line in func.lines.iter_mut() {
+                        line.address = line
+                            .address
+                            .wrapping_sub(old_address)
+                            .wrapping_add(new_address);
+                    }
+                }
+            }
+            JIT_CODE_DEBUG_INFO => {
+                let address = record.u64()?;
+                let num_entries = record.u64()?;
+                let mut lines = vec![];
+                let mut prev_file = String::new();
+                for _ in 0..num_entries {
+                    let line_address = record.u64()?;
+                    let line = record.u32()?;
+                    // The spec calls this the "column discriminator", and
+                    // SpiderMonkey puts the column here.
+                    let column = record.u32()?;
+                    let file = record.string()?;
+                    // A file name of "\xff" means the same file as the
+                    // previous entry.
+                    if file != b"\xff" {
+                        prev_file = String::from_utf8_lossy(file).into_owned();
+                    }
+                    lines.push(JitLine {
+                        address: line_address,
+                        line,
+                        column,
+                        file: prev_file.clone(),
+                    });
+                }
+                debug_infos.insert(address, lines);
+            }
+            _ => {}
+        }
+    }
+    Ok(funcs)
+}
@@ -0,0 +1,402 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lookup of local copies of binaries in the `--local` directories, by file
+//! name and, when the input identifies the build of a binary, by build ID.
+
+use crate::LocalFileInfo;
+use fxhash::FxHashMap;
+use goblin::container::Ctx;
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::program_header::{ProgramHeader, PT_LOAD, PT_NOTE};
+use goblin::elf::Elf;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use symbolic_common::DebugId;
+use symbolic_debuginfo::Object;
+
+/// An identifier for a particular build of a binary, as found in the input.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BuildId {
+    /// A code ID, e.g. an ELF build ID, as lowercase hex.
+    Code(String),
+    /// A debug ID, e.g. from a Breakpad module list.
+    Debug(DebugId),
+}
+
+impl BuildId {
+    pub fn code(code_id: &str) -> BuildId {
+        BuildId::Code(code_id.to_ascii_lowercase())
+    }
+
+    fn matches(&self, ids: &Option<(String, DebugId)>) -> bool {
+        match (self, ids) {
+            (BuildId::Code(code_id), Some((file_code_id, _))) => code_id == file_code_id,
+            (BuildId::Debug(debug_id), Some((_, file_debug_id))) => debug_id == file_debug_id,
+            (_, None) => false,
+        }
+    }
+}
+
+impl fmt::Display for BuildId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildId::Code(code_id) => write!(f, "{}", code_id),
+            BuildId::Debug(debug_id) => write!(f, "{}", debug_id.breakpad()),
+        }
+    }
+}
+
+fn read_magic(path: &Path) -> Option<[u8; 4]> {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .ok()?;
+    Some(magic)
+}
+
+fn is_elf(path: &Path) -> bool {
+    read_magic(path) == Some(*b"\x7fELF")
+}
+
+/// Could this be a binary? ELF, PE, and (fat) Mach-O files are recognized
+/// by their first four bytes, which avoids reading all the other files in
+/// the local directories.
+fn has_binary_magic(path: &Path) -> bool {
+    match read_magic(path) {
+        Some(magic) => {
+            magic == *b"\x7fELF"
+                || magic[..2] == *b"MZ"
+                || matches!(
+                    u32::from_le_bytes(magic),
+                    0xfeedface | 0xfeedfacf | 0xcafebabe | 0xbebafeca
+                )
+        }
+        None => false,
+    }
+}
+
+/// Find the contents of the `NT_GNU_BUILD_ID` note in the notes from a
+/// `PT_NOTE` segment.
+fn find_build_id(notes: &[u8], align: usize, little_endian: bool) -> Option<&[u8]> {
+    let word = |offset: usize| -> Option<usize> {
+        let bytes: [u8; 4] = notes.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        let word = if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        };
+        Some(word as usize)
+    };
+    let aligned = |len: usize| len.checked_add(align - 1).map(|len| len & !(align - 1));
+    let mut offset = 0;
+    while offset < notes.len() {
+        let (name_size, desc_size, note_type) =
+            (word(offset)?, word(offset + 4)?, word(offset + 8)?);
+        let name_start = offset + 12;
+        let desc_start = name_start.checked_add(aligned(name_size)?)?;
+        let desc = notes.get(desc_start..desc_start.checked_add(desc_size)?)?;
+        let name = notes.get(name_start..name_start + name_size)?;
+        if note_type == NT_GNU_BUILD_ID as usize && name == b"GNU\0" {
+            return Some(desc);
+        }
+        offset = desc_start + aligned(desc_size)?;
+    }
+    None
+}
+
+/// Read `len` bytes at `offset` within the file that starts at `base` within
+/// `file`.
+fn read_at(file: &mut File, base: u64, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(base.checked_add(offset)?)).ok()?;
+    file.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+/// Read the program headers of the ELF file that starts at `base` within
+/// `file`, without reading the rest of it. Returns `None` if it isn't an ELF
+/// file.
+fn read_program_headers(file: &mut File, base: u64) -> Option<(Ctx, Vec<ProgramHeader>)> {
+    // The header is bigger for 64-bit files.
+    let ident = read_at(file, base, 0, 16)?;
+    if ident[..4] != *b"\x7fELF" {
+        return None;
+    }
+    let header_size = if ident[4] == 2 { 64 } else { 52 };
+    let header = Elf::parse_header(&read_at(file, base, 0, header_size)?).ok()?;
+    let ctx = Ctx::new(header.container().ok()?, header.endianness().ok()?);
+    let phnum = header.e_phnum as usize;
+    let phdrs_data = read_at(file, base, header.e_phoff, phnum * ProgramHeader::size(ctx))?;
+    let phdrs = ProgramHeader::parse(&phdrs_data, 0, phnum, ctx).ok()?;
+    Some((ctx, phdrs))
+}
+
+/// The loadable segments of an ELF file, as `(file offset, address, size)`,
+/// for converting file offsets to addresses. The addresses are relative to
+/// the first segment, like those in the debug info.
+#[derive(Default)]
+pub struct Segments(Vec<(u64, u64, u64)>);
+
+impl Segments {
+    /// Read the loadable segments of the ELF file that starts at `base`
+    /// within `file`. Only the headers are read. Other files have no
+    /// segments.
+    pub fn read(file: &mut File, base: u64) -> Segments {
+        let phdrs = match read_program_headers(file, base) {
+            Some((_, phdrs)) => phdrs,
+            None => return Segments::default(),
+        };
+        let loads: Vec<_> = phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD).collect();
+        let first = loads.first().map_or(0, |phdr| phdr.p_vaddr);
+        Segments(
+            loads
+                .iter()
+                .map(|phdr| {
+                    (
+                        phdr.p_offset,
+                        phdr.p_vaddr.wrapping_sub(first),
+                        phdr.p_filesz,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Convert an offset within the file to an address, as used in its debug
+    /// info. These are the same unless the loadable segments are at
+    /// addresses that differ from their offsets, e.g. in a non-PIE
+    /// executable, or a library linked by `lld`.
+    pub fn address(&self, file_offset: u64) -> u64 {
+        self.0
+            .iter()
+            .find(|(offset, _, size)| *offset <= file_offset && file_offset - offset < *size)
+            .map_or(file_offset, |(offset, address, _)| {
+                (file_offset - offset).wrapping_add(*address)
+            })
+    }
+}
+
+/// Get the code ID and debug ID of the ELF file that starts at `base` within
+/// `file`, from its build ID. Only the ELF header, the program headers and
+/// the note segments are read, so this is much cheaper than parsing the
+/// whole file. Returns `None` if it isn't an ELF file, or has no build ID
+/// note in a note segment.
+pub fn read_elf_ids(file: &mut File, base: u64) -> Option<(String, DebugId)> {
+    // Note segments are small, so anything bigger is bogus.
+    const MAX_NOTES_SIZE: u64 = 1 << 20;
+
+    let (ctx, phdrs) = read_program_headers(file, base)?;
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_NOTE) {
+        if phdr.p_filesz > MAX_NOTES_SIZE {
+            continue;
+        }
+        let notes = read_at(file, base, phdr.p_offset, phdr.p_filesz as usize)?;
+        let align = if phdr.p_align == 8 { 8 } else { 4 };
+        let little_endian = ctx.is_little_endian();
+        let build_id = match find_build_id(&notes, align, little_endian) {
+            Some(build_id) if !build_id.is_empty() => build_id,
+            _ => continue,
+        };
+
+        // The debug ID is the first 16 bytes of the build ID, read as a GUID
+        // in the file's byte order, as Breakpad and `symbolic` do.
+        let code_id: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut guid = [0; 16];
+        let len = build_id.len().min(16);
+        guid[..len].copy_from_slice(&build_id[..len]);
+        if !little_endian {
+            guid[0..4].reverse();
+            guid[4..6].reverse();
+            guid[6..8].reverse();
+        }
+        let debug_id = DebugId::from_guid_age(&guid, 0).ok()?;
+        return Some((code_id, debug_id));
+    }
+    None
+}
+
+/// Get the code ID and debug ID of `path`.
+fn read_ids(path: &Path) -> Option<(String, DebugId)> {
+    if !has_binary_magic(path) {
+        return None;
+    }
+    if let Some(ids) = File::open(path)
+        .ok()
+        .and_then(|mut file| read_elf_ids(&mut file, 0))
+    {
+        return Some(ids);
+    }
+
+    // Other formats, and ELF files without a build ID note, must be parsed
+    // fully.
+    let data = fs::read(path).ok()?;
+    let object = Object::parse(&data).ok()?;
+    let code_id = object.code_id().map(|id| id.as_str().to_ascii_lowercase());
+    Some((code_id.unwrap_or_default(), object.debug_id()))
+}
+
+/// The warning for a file that was rejected because of its build ID.
+fn mismatch_warning(path: &Path, ids: &Option<(String, DebugId)>, build_id: &BuildId) -> String {
+    let other_id = match ids {
+        Some((code_id, _)) if matches!(build_id, BuildId::Code(_)) => code_id.clone(),
+        Some((_, debug_id)) => debug_id.breakpad().to_string(),
+        None => "(none)".to_string(),
+    };
+    format!(
+        "ignoring `{}` because its build ID `{}` doesn't match `{}`",
+        path.display(),
+        other_id,
+        build_id
+    )
+}
+
+/// Does the binary at `path` have the given build ID? If not, returns the
+/// warning to print.
+pub fn verify(path: &Path, build_id: &BuildId) -> Result<(), String> {
+    verify_ids(path, &read_ids(path), build_id)
+}
+
+/// Like `verify`, but for IDs that have already been read, e.g. from a
+/// library within an APK.
+pub fn verify_ids(
+    path: &Path,
+    ids: &Option<(String, DebugId)>,
+    build_id: &BuildId,
+) -> Result<(), String> {
+    if build_id.matches(ids) {
+        Ok(())
+    } else {
+        Err(mismatch_warning(path, ids, build_id))
+    }
+}
+
+pub struct LocalIndex {
+    info: LocalFileInfo,
+
+    /// The files in the local directories, by file name, in the order the
+    /// directories were given. Built on first use.
+    by_name: Option<FxHashMap<OsString, Vec<PathBuf>>>,
+
+    /// The code ID and debug ID of files that have been read.
+    ids: FxHashMap<PathBuf, Option<(String, DebugId)>>,
+
+    /// The results of previous lookups, so that each warning is only printed
+    /// once.
+    found: FxHashMap<(OsString, Option<BuildId>), Option<PathBuf>>,
+
+    /// The warnings for files rejected because of their build ID since the
+    /// last call to `take_mismatches`.
+    mismatches: Vec<String>,
+}
+
+impl LocalIndex {
+    pub fn new(info: LocalFileInfo) -> LocalIndex {
+        LocalIndex {
+            info,
+            by_name: None,
+            ids: FxHashMap::default(),
+            found: FxHashMap::default(),
+            mismatches: vec![],
+        }
+    }
+
+    fn add_dir(by_name: &mut FxHashMap<OsString, Vec<PathBuf>>, dir: &Path, recursive: bool) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+        paths.sort_by_key(|entry| entry.file_name());
+        for entry in paths {
+            let path = entry.path();
+            // Symlinks to directories aren't followed, to avoid cycles.
+            if matches!(entry.file_type(), Ok(t) if t.is_dir()) {
+                if recursive {
+                    LocalIndex::add_dir(by_name, &path, recursive);
+                }
+            } else if path.is_file() {
+                by_name.entry(entry.file_name()).or_default().push(path);
+            }
+        }
+    }
+
+    fn by_name(&mut self) -> &FxHashMap<OsString, Vec<PathBuf>> {
+        let info = &self.info;
+        self.by_name.get_or_insert_with(|| {
+            let mut by_name = FxHashMap::default();
+            for dir in &info.local_dirs {
+                LocalIndex::add_dir(&mut by_name, dir, info.recursive);
+            }
+            by_name
+        })
+    }
+
+    fn ids(&mut self, path: &Path) -> &Option<(String, DebugId)> {
+        self.ids
+            .entry(path.to_path_buf())
+            .or_insert_with(|| read_ids(path))
+    }
+
+    /// Find a local copy of `file_name`, which may be a path, in which case
+    /// only its last component is considered. If `build_id` is given, the
+    /// copy must have that build ID. A copy with the same file name is
+    /// preferred, but if none match, every binary in the local directories is
+    /// considered.
+    pub fn find(&mut self, file_name: &str, build_id: Option<&BuildId>) -> Option<String> {
+        let file_name = Path::new(file_name).file_name()?.to_os_string();
+        let key = (file_name, build_id.cloned());
+        if let Some(found) = self.found.get(&key) {
+            return found.as_ref().map(|path| path.display().to_string());
+        }
+
+        let found = self.find_uncached(&key.0, build_id);
+        let result = found.as_ref().map(|path| path.display().to_string());
+        self.found.insert(key, found);
+        result
+    }
+
+    /// Get the warnings for files rejected because of their build ID, and
+    /// clear them.
+    pub fn take_mismatches(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.mismatches)
+    }
+
+    fn find_uncached(&mut self, file_name: &OsStr, build_id: Option<&BuildId>) -> Option<PathBuf> {
+        let candidates = self.by_name().get(file_name).cloned().unwrap_or_default();
+        let build_id = match build_id {
+            Some(build_id) => build_id,
+            None => return candidates.into_iter().next(),
+        };
+
+        for candidate in &candidates {
+            let ids = self.ids(candidate);
+            if build_id.matches(ids) {
+                return Some(candidate.clone());
+            }
+            let warning = mismatch_warning(candidate, ids, build_id);
+            self.mismatches.push(warning);
+        }
+
+        // Look for a binary with a different file name, e.g. an unstripped
+        // copy of a library. Only ELF files are considered, because their IDs
+        // can be read from their headers, and there may be many files.
+        let mut others: Vec<_> = self
+            .by_name()
+            .iter()
+            .filter(|(name, _)| *name != file_name)
+            .flat_map(|(_, paths)| paths.iter().cloned())
+            .filter(|path| is_elf(path))
+            .collect();
+        others.sort();
+        others
+            .into_iter()
+            .find(|other| build_id.matches(self.ids(other)))
+    }
+}
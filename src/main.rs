@@ -3,22 +3,59 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use fxhash::{FxHashMap, FxHashSet};
-use goblin::{archive, mach};
+use goblin::{archive, mach, pe};
 use regex::Regex;
+use std::borrow::Cow;
 use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
 use std::env;
 use std::fs;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::str;
-use symbolic_common::{Arch, Language, Name, NameMangling};
+use std::time::Instant;
+use symbolic_common::{Arch, DebugId};
 use symbolic_debuginfo::{Archive, FileFormat, Function, Object, ObjectDebugSession};
-use symbolic_demangle::{Demangle, DemangleOptions};
 
+mod apk;
+mod config;
+mod demangle;
+mod diag;
+mod jit;
+mod local;
+mod pathmap;
+mod perf;
+mod profile;
+mod server;
+mod signature;
+mod srcsrv;
+mod template;
 #[cfg(test)]
 mod tests;
+mod tombstone;
+mod vcs;
+
+use apk::ApkEntry;
+use config::{Config, FramePattern};
+use demangle::NameOptions;
+use diag::{FailOn, Level, Resolution, Stats};
+use local::{BuildId, LocalIndex, Segments};
+use pathmap::PathMap;
+use perf::{PerfInfo, PerfLine};
+use profile::{Lib, Symbol};
+use serde_json::{json, Value};
+use server::{Request, Response};
+use signature::SignatureInfo;
+use srcsrv::SrcSrv;
+use template::Templates;
+use tombstone::TombstoneParser;
+use vcs::{SourceLinkInfo, VcsPath, VcsScheme};
 
 /// Should debugging output for functions and lines be printed? (See
 /// `tests/README.md` for more details.)
@@ -54,6 +91,21 @@ impl Interner {
     pub fn get(&self, interned_string: InternedString) -> &str {
         &self.strings[interned_string.0]
     }
+
+    /// Replace every string for which `f` returns a new value.
+    fn remap(&mut self, f: impl Fn(&str) -> Option<String>) {
+        for string in self.strings.iter_mut() {
+            if let Some(new_string) = f(string) {
+                *string = new_string;
+            }
+        }
+        self.map = self
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (string.clone(), index))
+            .collect();
+    }
 }
 
 enum JsonMode {
@@ -68,7 +120,7 @@ fn format_address(address: u64, offset: i64) -> String {
         format!(
             "0x{:x} -> 0x{:x}",
             address,
-            (address as i64 + offset) as u64
+            (address as i64).wrapping_add(offset) as u64
         )
     }
 }
@@ -76,7 +128,12 @@ fn format_address(address: u64, offset: i64) -> String {
 /// Debug info for a single line.
 struct LineInfo {
     address: u64,
-    line: u64,
+
+    // These are `u32`s to keep `LineInfo` small, because there are many of
+    // them. A column of 0 means it is unknown, which is always the case
+    // with native debug info, because `symbolic` doesn't provide columns.
+    line: u32,
+    column: u32,
 
     /// We use `InternedString` here because paths are often duplicated.
     path: InternedString,
@@ -93,8 +150,9 @@ impl LineInfo {
             );
         }
         LineInfo {
-            address: (line.address as i64 + offset) as u64,
-            line: line.line,
+            address: (line.address as i64).wrapping_add(offset) as u64,
+            line: u32::try_from(line.line).unwrap_or(u32::MAX),
+            column: 0,
             path: interner.intern(line.file.path_str()),
         }
     }
@@ -111,10 +169,20 @@ struct FuncInfo {
 
     // The `LineInfos` are sorted by `address`.
     line_infos: Box<[LineInfo]>,
+
+    // The functions inlined into this one, if `keep_inlinees` was set. The
+    // line info of this function at an address within an inlinee is the
+    // location of the call.
+    inlinees: Box<[FuncInfo]>,
 }
 
 impl FuncInfo {
-    fn new(interner: &mut Interner, function: Function, offset: i64) -> FuncInfo {
+    fn new(
+        interner: &mut Interner,
+        mut function: Function,
+        offset: i64,
+        keep_inlinees: bool,
+    ) -> FuncInfo {
         if PRINT_FUNCS_AND_LINES {
             eprintln!(
                 "FUNC {} size={} func={}",
@@ -123,8 +191,16 @@ impl FuncInfo {
                 function.name.as_str()
             );
         }
+        let inlinees: Box<[FuncInfo]> = if keep_inlinees {
+            std::mem::take(&mut function.inlinees)
+                .into_iter()
+                .map(|inlinee| FuncInfo::new(interner, inlinee, offset, true))
+                .collect()
+        } else {
+            Box::new([])
+        };
         FuncInfo {
-            address: (function.address as i64 + offset) as u64,
+            address: (function.address as i64).wrapping_add(offset) as u64,
             size: function.size,
             mangled_name: function.name.as_str().to_string(),
             line_infos: function
@@ -132,22 +208,42 @@ impl FuncInfo {
                 .into_iter()
                 .map(|line| LineInfo::new(interner, line, offset))
                 .collect(),
+            inlinees,
         }
     }
 
-    fn demangled_name(&self) -> String {
-        let options = DemangleOptions::complete();
-        Name::new(
-            self.mangled_name.as_str(),
-            NameMangling::Mangled,
-            Language::Unknown,
-        )
-        .try_demangle(options)
-        .to_string()
+    /// Create a `FuncInfo` with a name and bounds but no line info, e.g. for a
+    /// function found only in a symbol table.
+    fn name_only(address: u64, size: u64, mangled_name: String) -> FuncInfo {
+        if PRINT_FUNCS_AND_LINES {
+            eprintln!(
+                "FUNC {} size={} func={}",
+                format_address(address, 0),
+                size,
+                mangled_name
+            );
+        }
+        FuncInfo {
+            address,
+            size,
+            mangled_name,
+            line_infos: Box::new([]),
+            inlinees: Box::new([]),
+        }
+    }
+
+    /// The demangled name, in the `--demangle` style.
+    fn demangled_name(&self, options: &NameOptions) -> String {
+        options.display_name(&self.mangled_name)
+    }
+
+    /// The demangled name without parameters or return type.
+    fn short_name(&self, options: &NameOptions) -> String {
+        options.short_name(&self.mangled_name)
     }
 
     fn contains(&self, address: u64) -> bool {
-        self.address <= address && address < self.address + self.size
+        self.address <= address && address - self.address < self.size
     }
 
     fn line_info(&self, address: u64) -> Option<&LineInfo> {
@@ -160,6 +256,95 @@ impl FuncInfo {
             Err(next_index) => Some(&self.line_infos[next_index - 1]),
         }
     }
+
+    /// Get the chain of functions inlined at `address`, starting with this
+    /// function and ending with the innermost inlinee.
+    fn inline_chain(&self, address: u64) -> Vec<&FuncInfo> {
+        let mut chain = vec![self];
+        let mut func_info = self;
+        while let Some(inlinee) = func_info.inlinees.iter().find(|f| f.contains(address)) {
+            chain.push(inlinee);
+            func_info = inlinee;
+        }
+        chain
+    }
+}
+
+/// Where the debug info in a `FileInfo` came from. The bin file itself is
+/// implied for the variants without a path.
+#[derive(Clone, Default)]
+enum Source {
+    /// Nothing, because the file couldn't be read.
+    #[default]
+    None,
+
+    /// The debug info within the binary itself.
+    Native,
+
+    /// A Breakpad symbols file.
+    Breakpad(PathBuf),
+
+    /// A PDB file.
+    Pdb(PathBuf),
+
+    /// The object files referenced by a Mac binary's symbol table.
+    Oso,
+
+    /// Function names from a symbol table, without line info.
+    SymbolTable,
+
+    /// Function names from a PE export table, without line info.
+    Exports,
+
+    /// The `--jit-map` files.
+    Jit,
+}
+
+impl Source {
+    /// A short name for the kind of source, as used in annotations.
+    fn kind(&self) -> &'static str {
+        match self {
+            Source::None => "none",
+            Source::Native => "native",
+            Source::Breakpad(_) => "breakpad",
+            Source::Pdb(_) => "pdb",
+            Source::Oso => "oso",
+            Source::SymbolTable => "symtab",
+            Source::Exports => "exports",
+            Source::Jit => "jit",
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Source::Breakpad(path) | Source::Pdb(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The source as a JSON object, for structured output.
+    fn to_json(&self) -> Value {
+        let mut json = json!({ "kind": self.kind() });
+        if let Some(path) = self.path() {
+            json["path"] = json!(path.display().to_string());
+        }
+        json
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::None => write!(f, "nowhere"),
+            Source::Native => write!(f, "native debug info"),
+            Source::Breakpad(path) => write!(f, "Breakpad symbols `{}`", path.display()),
+            Source::Pdb(path) => write!(f, "PDB file `{}`", path.display()),
+            Source::Oso => write!(f, "object files"),
+            Source::SymbolTable => write!(f, "symbol table"),
+            Source::Exports => write!(f, "export table"),
+            Source::Jit => write!(f, "JIT files"),
+        }
+    }
 }
 
 /// Debug info for a single file.
@@ -169,17 +354,24 @@ struct FileInfo {
 
     /// The `FuncInfo`s are sorted by `address`.
     func_infos: Vec<FuncInfo>,
+
+    source: Source,
+
+    /// Debug info that was found but not used because its ID didn't match,
+    /// e.g. a stale PDB file, when a fallback was used instead. It is
+    /// reported when the file is loaded.
+    mismatch: Option<anyhow::Error>,
 }
 
 impl FileInfo {
-    fn new(debug_session: ObjectDebugSession) -> FileInfo {
+    fn new(debug_session: ObjectDebugSession, source: Source, keep_inlinees: bool) -> FileInfo {
         // Build the `FileInfo` from the debug session.
         let mut interner = Interner::default();
         let mut func_infos: Vec<_> = debug_session
             .functions()
             .filter_map(|function| {
                 let function = function.ok()?;
-                Some(FuncInfo::new(&mut interner, function, 0))
+                Some(FuncInfo::new(&mut interner, function, 0, keep_inlinees))
             })
             .collect();
         func_infos.sort_unstable_by_key(|func_info| func_info.address);
@@ -188,6 +380,8 @@ impl FileInfo {
         FileInfo {
             interner,
             func_infos,
+            source,
+            mismatch: None,
         }
     }
 
@@ -200,6 +394,7 @@ impl FileInfo {
         debug_session: ObjectDebugSession,
         interner: &mut Interner,
         func_infos: &mut Vec<FuncInfo>,
+        keep_inlinees: bool,
     ) {
         // Build the `FileInfo` from the debug session.
         func_infos.extend(debug_session.functions().filter_map(|function| {
@@ -215,20 +410,22 @@ impl FileInfo {
             // match that address.
             let sym_func_key = Fixer::sym_func_key(file_name, function.name.as_str());
             let sym_func_addr = sym_func_addrs.get(&sym_func_key)?;
-            let offset = *sym_func_addr as i64 - function.address as i64;
-            Some(FuncInfo::new(interner, function, offset))
+            let offset = (*sym_func_addr as i64).wrapping_sub(function.address as i64);
+            Some(FuncInfo::new(interner, function, offset, keep_inlinees))
         }));
     }
 
     /// Finish constructing a `FileInfo` that has been built up using
     /// `Fixer::add`.
-    fn finish(interner: Interner, mut func_infos: Vec<FuncInfo>) -> FileInfo {
+    fn finish(interner: Interner, mut func_infos: Vec<FuncInfo>, source: Source) -> FileInfo {
         func_infos.sort_unstable_by_key(|func_info| func_info.address);
         func_infos.dedup_by_key(|func_info| func_info.address);
 
         FileInfo {
             func_infos,
             interner,
+            source,
+            mismatch: None,
         }
     }
 
@@ -252,13 +449,107 @@ impl FileInfo {
     }
 }
 
+/// An error for debug info that was found only with the wrong ID, i.e. it is
+/// probably stale.
+#[derive(Debug)]
+struct IdMismatch(String);
+
+impl std::fmt::Display for IdMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IdMismatch {}
+
 /// Info provided via the `-b` flag.
 struct BreakpadInfo {
     syms_dir: String,
 }
 
+/// Info provided via the `--jit-map` flag.
+#[derive(Default)]
+struct JitInfo {
+    /// The perf map and jitdump files.
+    jit_files: Vec<String>,
+
+    /// The functions from all of `jit_files`, as a pseudo-module with
+    /// absolute addresses.
+    file_info: FileInfo,
+}
+
+/// Info provided via the `-l` and `--local-recursive` flags.
 struct LocalFileInfo {
-    local_dir: String,
+    local_dirs: Vec<PathBuf>,
+    recursive: bool,
+}
+
+/// Info provided via the `--pdb-dir`, `--symbol-path` and `--srcsrv` flags
+/// (and the `_NT_SYMBOL_PATH` environment variable), used to find and read PDB
+/// files.
+#[derive(Default)]
+struct PdbInfo {
+    /// Directories that may contain `name.pdb` directly.
+    dirs: Vec<PathBuf>,
+
+    /// Symbol store directories, laid out as `name.pdb/<GUID+age>/name.pdb`.
+    stores: Vec<PathBuf>,
+
+    /// Should source paths be replaced with their version control locations
+    /// from the `srcsrv` stream?
+    srcsrv: bool,
+}
+
+impl PdbInfo {
+    /// Add the local directories from a symbol path in the `_NT_SYMBOL_PATH`
+    /// format, e.g. `srv*c:\symcache*https://symbols.example.com;c:\pdbs`.
+    /// Symbol servers are ignored because we don't download anything.
+    fn add_symbol_path(&mut self, symbol_path: &str) {
+        for elem in symbol_path.split(';').map(str::trim) {
+            let lower = elem.to_ascii_lowercase();
+            if lower.starts_with("srv*")
+                || lower.starts_with("symsrv*")
+                || lower.starts_with("cache*")
+            {
+                // E.g. `srv*<downstream store>*...*<server>`. (For `symsrv`
+                // the first field after the prefix is the DLL name, which
+                // won't exist as a directory, so it is harmless to include.)
+                let stores = elem.split('*').skip(1).filter(|s| {
+                    !s.is_empty() && !s.starts_with("http://") && !s.starts_with("https://")
+                });
+                self.stores.extend(stores.map(PathBuf::from));
+            } else if !elem.is_empty() {
+                self.dirs.push(PathBuf::from(elem));
+            }
+        }
+    }
+
+    /// The paths at which to look for a PDB file, in the order used by
+    /// Windows debuggers: the path recorded in the PE file, the directory
+    /// containing the PE file, user-given directories, and symbol stores.
+    fn candidates(&self, bin_file: &str, pdb_file_name: &str, debug_id: DebugId) -> Vec<PathBuf> {
+        // The recorded path is often a Windows path, which must be split on
+        // backslashes even when we're not running on Windows.
+        let pdb_base = pdb_file_name
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or(pdb_file_name);
+
+        let mut candidates = vec![PathBuf::from(pdb_file_name)];
+        if let Some(bin_dir) = Path::new(bin_file).parent() {
+            candidates.push(bin_dir.join(pdb_base));
+        }
+        candidates.extend(self.dirs.iter().map(|dir| dir.join(pdb_base)));
+        let id_seg = debug_id.breakpad().to_string();
+        candidates.extend(
+            self.stores
+                .iter()
+                .map(|store| store.join(pdb_base).join(&id_seg).join(pdb_base)),
+        );
+        let mut seen = FxHashSet::default();
+        candidates.retain(|candidate| seen.insert(candidate.clone()));
+        candidates
+    }
 }
 
 trait CpuArch {
@@ -307,7 +598,34 @@ struct Fixer {
     file_infos: FxHashMap<String, FileInfo>,
     json_mode: JsonMode,
     bp_info: Option<BreakpadInfo>,
-    local_info: Option<LocalFileInfo>,
+    local_index: Option<LocalIndex>,
+    module_ids: FxHashMap<String, BuildId>,
+    pdb_info: PdbInfo,
+    jit_info: JitInfo,
+    source_link_info: Option<SourceLinkInfo>,
+    vcs_schemes: Vec<VcsScheme>,
+    path_map: PathMap,
+    apk_entries: FxHashMap<String, Vec<ApkEntry>>,
+    apk_remaps: FxHashMap<String, Option<String>>,
+    elf_segments: FxHashMap<String, Segments>,
+    tombstone_parser: TombstoneParser,
+    build_id_dirs: Vec<PathBuf>,
+    tombstone_files: FxHashMap<(String, Option<BuildId>), Option<String>>,
+    perf_info: Option<PerfInfo>,
+
+    /// Should the functions inlined into each function be kept? They are
+    /// only needed in `perf script` mode and by the server, and cost a lot
+    /// of memory.
+    keep_inlinees: bool,
+    stats: Stats,
+
+    /// How much is printed to stderr.
+    level: Level,
+    show_source: bool,
+    templates: Templates,
+    frame_patterns: Vec<FramePattern>,
+    signature: Option<SignatureInfo>,
+    name_options: NameOptions,
     lb: char,
     rb: char,
 }
@@ -321,8 +639,9 @@ impl Fixer {
         bp_info: Option<BreakpadInfo>,
         local_info: Option<LocalFileInfo>,
     ) -> Fixer {
-        // We use parentheses with native debug info, and square brackets with
-        // Breakpad symbols.
+        // The brackets used for frames without debug info, which are those
+        // that Breakpad symbols would be used for, if present. (See
+        // `brackets`.)
         let (lb, rb) = if bp_info.is_none() {
             ('(', ')')
         } else {
@@ -330,11 +649,32 @@ impl Fixer {
         };
         Fixer {
             // Matches lines produced by MozFormatCodeAddress().
-            re: Regex::new(r"^(.*#\d+: )(.+)\[(.+) \+0x([0-9A-Fa-f]+)\](.*)$").unwrap(),
+            re: Regex::new(r"^(.*#(\d+): )(.+)\[(.+) \+0x([0-9A-Fa-f]+)\](.*)$").unwrap(),
             file_infos: FxHashMap::default(),
             json_mode,
             bp_info,
-            local_info,
+            local_index: local_info.map(LocalIndex::new),
+            module_ids: FxHashMap::default(),
+            pdb_info: PdbInfo::default(),
+            jit_info: JitInfo::default(),
+            source_link_info: None,
+            vcs_schemes: VcsScheme::builtin(),
+            path_map: PathMap::default(),
+            apk_entries: FxHashMap::default(),
+            elf_segments: FxHashMap::default(),
+            apk_remaps: FxHashMap::default(),
+            tombstone_parser: TombstoneParser::new(),
+            build_id_dirs: vec![],
+            tombstone_files: FxHashMap::default(),
+            perf_info: None,
+            keep_inlinees: false,
+            stats: Stats::default(),
+            level: Level::default(),
+            show_source: false,
+            templates: Templates::default(),
+            frame_patterns: vec![],
+            signature: None,
+            name_options: NameOptions::default(),
             lb,
             rb,
         }
@@ -349,46 +689,168 @@ impl Fixer {
         escaped[1..escaped.len() - 1].to_string()
     }
 
-    /// Remove JSON escapes from a fragment of text.
-    fn json_unescape(string: &str) -> String {
+    /// Remove JSON escapes from a fragment of text. Returns `None` if the
+    /// fragment isn't valid within a JSON string.
+    fn json_unescape(string: &str) -> Option<String> {
         // Add quotes.
         let quoted = format!("\"{}\"", string);
 
         // Do the unescaping, which also removes the quotes.
-        let value = serde_json::from_str(&quoted).unwrap();
-        if let serde_json::Value::String(unescaped) = value {
-            unescaped
+        match serde_json::from_str(&quoted) {
+            Ok(serde_json::Value::String(unescaped)) => Some(unescaped),
+            _ => None,
+        }
+    }
+
+    /// The brackets to put around a source location found in `source`. We
+    /// use parentheses with native debug info, and square brackets with
+    /// Breakpad symbols. (Breakpad symbols may be missing for some files,
+    /// which then fall back to native debug info.)
+    fn brackets(&self, source: &Source) -> (char, char) {
+        match source {
+            Source::None => (self.lb, self.rb),
+            Source::Breakpad(_) => ('[', ']'),
+            _ => ('(', ')'),
+        }
+    }
+
+    /// The annotation added to a frame fixed using `source`, if
+    /// `--show-source` was given, e.g. ` {native}` or
+    /// ` {breakpad: syms/libxul.so/<uuid>/libxul.so.sym}`.
+    fn source_annotation(&self, source: &Source) -> String {
+        if !self.show_source {
+            return String::new();
+        }
+        let raw_annotation = match source.path() {
+            Some(path) => format!(" {{{}: {}}}", source.kind(), path.display()),
+            None => format!(" {{{}}}", source.kind()),
+        };
+        if let JsonMode::Yes = self.json_mode {
+            Fixer::json_escape(&raw_annotation)
         } else {
-            panic!()
+            raw_annotation
+        }
+    }
+
+    /// Print a warning about a line that looks like it contains a stack
+    /// frame but can't be processed. The line is output unchanged.
+    fn warn_malformed(&self, line: &str, problem: &str) {
+        diag::warning(
+            self.level,
+            format!("ignoring malformed frame ({}): {}", problem, line),
+        );
+    }
+
+    /// Add a frame that wasn't looked up, e.g. because it is malformed, to
+    /// the signature, if `--signature` was given. The function name from the
+    /// input is used if there is one. `offset` is in hex, as in the input.
+    fn push_unresolved(&mut self, in_func_name: Option<&str>, module: Option<&str>, offset: &str) {
+        if let Some(signature) = &mut self.signature {
+            signature.push(match in_func_name {
+                Some(in_func_name) if in_func_name != "???" => signature::normalize(in_func_name),
+                _ => signature::unresolved(module, offset),
+            });
         }
     }
 
     /// Read the data from `file_name` and construct a `FileInfo` that we can
     /// subsequently query. Return a description of the failing operation on
     /// error.
-    fn build_file_info(bin_file: &str, bp_info: &Option<BreakpadInfo>) -> Result<FileInfo> {
+    fn build_file_info(
+        bin_file: &str,
+        bp_info: &Option<BreakpadInfo>,
+        pdb_info: &PdbInfo,
+        keep_inlinees: bool,
+    ) -> Result<FileInfo> {
         // If we're using Breakpad symbols, we don't consult `bin_file`.
         if let Some(bp_info) = bp_info {
-            if let Ok(res) = Fixer::build_file_info_breakpad(bin_file, bp_info) {
+            if let Ok(res) = Fixer::build_file_info_breakpad(bin_file, bp_info, keep_inlinees) {
                 return Ok(res);
             }
         }
 
         // Otherwise, we read `bin_file`.
-        let data = fs::read(bin_file).context("read")?;
+        let data = Fixer::read_bin_file(bin_file)?;
         let file_format = Archive::peek(&data);
         match file_format {
-            FileFormat::Elf => Fixer::build_file_info_direct(&data),
-            FileFormat::Pe => Fixer::build_file_info_pe(&data),
-            FileFormat::Pdb => Fixer::build_file_info_direct(&data),
-            FileFormat::MachO => Fixer::build_file_info_macho(&data),
+            FileFormat::Elf => Fixer::build_file_info_direct(&data, Source::Native, keep_inlinees),
+            FileFormat::Pe => Fixer::build_file_info_pe(bin_file, &data, pdb_info, keep_inlinees),
+            FileFormat::Pdb => {
+                let object = Object::parse(&data).context("parse")?;
+                Fixer::build_file_info_from_pdb(
+                    &object,
+                    Path::new(bin_file),
+                    pdb_info,
+                    keep_inlinees,
+                )
+                .context("read debug info from")
+            }
+            FileFormat::MachO => Fixer::build_file_info_macho(&data, keep_inlinees),
+            FileFormat::Breakpad => Fixer::build_file_info_direct(
+                &data,
+                Source::Breakpad(PathBuf::from(bin_file)),
+                keep_inlinees,
+            ),
             _ => bail!("parse {} format file", file_format),
         }
     }
 
+    /// Construct a `FileInfo` for the JIT code described by `jit_files`. Errors
+    /// are printed, and the functions from the other files are still used.
+    fn build_file_info_jit(jit_files: &[String], level: Level) -> FileInfo {
+        let mut interner = Interner::default();
+        let mut func_infos = vec![];
+        for jit_file in jit_files {
+            let jit_funcs = match jit::read_jit_file(jit_file) {
+                Ok(jit_funcs) => jit_funcs,
+                Err(err) => {
+                    diag::file_error(level, &err, jit_file);
+                    continue;
+                }
+            };
+            for jit_func in jit_funcs {
+                let mut func_info =
+                    FuncInfo::name_only(jit_func.address, jit_func.size, jit_func.name);
+                func_info.line_infos = jit_func
+                    .lines
+                    .into_iter()
+                    .map(|line| LineInfo {
+                        address: line.address,
+                        line: line.line,
+                        column: line.column,
+                        path: interner.intern(line.file),
+                    })
+                    .collect();
+                func_infos.push(func_info);
+            }
+        }
+        // JIT code can be discarded and its memory reused, in which case the
+        // later function is the one that matters.
+        func_infos.reverse();
+        func_infos.sort_by_key(|func_info| func_info.address);
+        func_infos.dedup_by_key(|func_info| func_info.address);
+
+        FileInfo {
+            interner,
+            func_infos,
+            source: Source::Jit,
+            mismatch: None,
+        }
+    }
+
+    /// Read the contents of `bin_file`, which may be a library within an APK.
+    fn read_bin_file(bin_file: &str) -> Result<Vec<u8>> {
+        if let Some((apk_file, entry_name)) = apk::split_path(bin_file) {
+            apk::read_entry(apk_file, entry_name).context("read")
+        } else {
+            fs::read(bin_file).context("read")
+        }
+    }
+
     fn build_file_info_breakpad(
         bin_file: &str,
         BreakpadInfo { syms_dir }: &BreakpadInfo,
+        keep_inlinees: bool,
     ) -> Result<FileInfo> {
         // We must find the `.sym` file for this `bin_file`, as produced by the
         // Firefox build system, which is in the symbols directory under
@@ -407,9 +869,8 @@ impl Fixer {
         let mut bin_base = bin_file
             .file_name()
             .context("read breakpad symbols for")?
-            .to_str()
-            .unwrap()
-            .to_string();
+            .to_string_lossy()
+            .into_owned();
         let is_win = bin_base.ends_with(".dll") || bin_base.ends_with(".exe");
         if is_win {
             bin_base.truncate(bin_base.len() - 4);
@@ -431,7 +892,7 @@ impl Fixer {
         // - Unix: `uuid_dir` is `syms/libxul.so/<uuid>/`
         // - Windows: `uuid_dir` is `syms/xul.pdb/<uuid>/`
         let uuid_dir = {
-            let data = fs::read(bin_file).context("read")?;
+            let data = Fixer::read_bin_file(&bin_file.to_string_lossy())?;
             let object = Object::parse(&data).context("parse")?;
             let uuid_seg = object.debug_id().breakpad().to_string();
             let mut uuid_dir = db_dir;
@@ -454,32 +915,191 @@ impl Fixer {
                 "note: this is expected and harmless for system libraries on debug automation runs",
             )
             .with_context(|| format!("read symbols file `{}` for", sym_file.display()))?;
-        Fixer::build_file_info_direct(&data)
+        Fixer::build_file_info_direct(&data, Source::Breakpad(sym_file), keep_inlinees)
     }
 
     // "Direct" means that the debug info is within `data`, as opposed to being
     // in another file that `data` refers to.
-    fn build_file_info_direct(data: &[u8]) -> Result<FileInfo> {
+    fn build_file_info_direct(
+        data: &[u8],
+        source: Source,
+        keep_inlinees: bool,
+    ) -> Result<FileInfo> {
         let object = Object::parse(data).context("parse")?;
         let debug_session = object.debug_session().context("read debug info from")?;
-        Ok(FileInfo::new(debug_session))
+        Ok(FileInfo::new(debug_session, source, keep_inlinees))
+    }
+
+    fn build_file_info_pe(
+        bin_file: &str,
+        data: &[u8],
+        pdb_info: &PdbInfo,
+        keep_inlinees: bool,
+    ) -> Result<FileInfo> {
+        // For PEs we get the debug info from a PDB file. If that can't be
+        // found (e.g. for system DLLs) we fall back to function names from the
+        // export table. A PDB file with the wrong ID is still reported,
+        // because it is probably stale.
+        match Fixer::build_file_info_pdb(bin_file, data, pdb_info, keep_inlinees) {
+            Ok(file_info) => Ok(file_info),
+            Err(err) => match Fixer::build_file_info_pe_exports(data) {
+                Ok(mut file_info) if !file_info.func_infos.is_empty() => {
+                    if err.chain().any(|cause| cause.is::<IdMismatch>()) {
+                        file_info.mismatch = Some(err);
+                    }
+                    Ok(file_info)
+                }
+                _ => Err(err),
+            },
+        }
     }
 
-    fn build_file_info_pe(data: &[u8]) -> Result<FileInfo> {
-        // For PEs we get the debug info from a PDB file.
+    fn build_file_info_pdb(
+        bin_file: &str,
+        data: &[u8],
+        pdb_info: &PdbInfo,
+        keep_inlinees: bool,
+    ) -> Result<FileInfo> {
         let pe_object = Object::parse(data).context("parse")?;
+        let debug_id = pe_object.debug_id();
         let pe = match pe_object {
             Object::Pe(pe) => pe,
-            _ => unreachable!(),
+            _ => bail!("parse"),
         };
         let pdb_file_name = pe.debug_file_name().context("find debug info file for")?;
-        let data = fs::read(pdb_file_name.to_string())
+
+        // The recorded path is usually an absolute path on the build machine,
+        // so we look in other places too. A PDB file is only used if its GUID
+        // and age match the PE file, because a stale PDB file would give
+        // plausible but wrong results.
+        let mut mismatches = vec![];
+        for candidate in pdb_info.candidates(bin_file, &pdb_file_name, debug_id) {
+            let data = match fs::read(&candidate) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            let object = match Object::parse(&data) {
+                Ok(object) => object,
+                Err(_) => {
+                    mismatches.push(format!("`{}` can't be parsed", candidate.display()));
+                    continue;
+                }
+            };
+            if object.debug_id() != debug_id {
+                mismatches.push(format!(
+                    "`{}` has ID {}",
+                    candidate.display(),
+                    object.debug_id().breakpad()
+                ));
+                continue;
+            }
+            return Fixer::build_file_info_from_pdb(&object, &candidate, pdb_info, keep_inlinees)
+                .with_context(|| format!("read debug info from `{}` for", candidate.display()));
+        }
+
+        let err = if mismatches.is_empty() {
+            anyhow!("no file found in any search location")
+        } else {
+            anyhow::Error::new(IdMismatch(format!(
+                "no file with ID {} found; {}",
+                debug_id.breakpad(),
+                mismatches.join(", ")
+            )))
+        };
+        Err(err
             .context("note: this is expected and harmless for all PDB files on opt automation runs")
-            .with_context(|| format!("read debug info file `{}` for", pdb_file_name))?;
-        Fixer::build_file_info_direct(&data)
+            .context(format!("read debug info file `{}` for", pdb_file_name)))
+    }
+
+    /// Build a `FileInfo` from a parsed PDB file, replacing source paths with
+    /// their version control locations if requested.
+    fn build_file_info_from_pdb(
+        object: &Object,
+        path: &Path,
+        pdb_info: &PdbInfo,
+        keep_inlinees: bool,
+    ) -> Result<FileInfo> {
+        let debug_session = object.debug_session()?;
+        let mut file_info = FileInfo::new(
+            debug_session,
+            Source::Pdb(path.to_path_buf()),
+            keep_inlinees,
+        );
+        if pdb_info.srcsrv {
+            if let Object::Pdb(pdb) = object {
+                // Many PDB files lack a `srcsrv` stream, which is fine.
+                if let Ok(stream) = pdb.inner().write().named_stream(b"srcsrv") {
+                    let srcsrv = SrcSrv::parse(&String::from_utf8_lossy(stream.as_slice()));
+                    file_info.interner.remap(|path| srcsrv.location(path));
+                }
+            }
+        }
+        Ok(file_info)
+    }
+
+    /// Build a `FileInfo` containing function names but no line info, using
+    /// names from the export table and function bounds from the `.pdata`
+    /// runtime function table.
+    fn build_file_info_pe_exports(data: &[u8]) -> Result<FileInfo> {
+        let pe = pe::PE::parse(data).context("parse (with goblin)")?;
+
+        // 32-bit x86 PE files have no `.pdata` section.
+        let mut runtime_functions = vec![];
+        if let Some(exception_data) = &pe.exception_data {
+            for function in exception_data.functions() {
+                let function = function.context("read runtime function table from")?;
+                runtime_functions
+                    .push((function.begin_address as u64, function.end_address as u64));
+            }
+        }
+        runtime_functions.sort_unstable();
+
+        // Forwarded exports refer to functions in other DLLs, so we skip
+        // them.
+        let mut exports: Vec<_> = pe
+            .exports
+            .iter()
+            .filter(|export| export.reexport.is_none())
+            .filter_map(|export| Some((export.rva as u64, export.name?)))
+            .collect();
+        exports.sort_unstable();
+
+        let func_infos = exports
+            .iter()
+            .enumerate()
+            .map(|(i, &(address, name))| {
+                // Use the runtime function containing the export if there is
+                // one. Otherwise, extend the function to the next export or
+                // to the end of its section.
+                let index = runtime_functions.partition_point(|&(begin, _)| begin <= address);
+                let end = match index.checked_sub(1).map(|i| runtime_functions[i]) {
+                    Some((_, end)) if address < end => end,
+                    _ => {
+                        let section_end = pe
+                            .sections
+                            .iter()
+                            .map(|s| (s.virtual_address as u64, s.virtual_size as u64))
+                            .find(|&(start, size)| start <= address && address < start + size)
+                            .map_or(address, |(start, size)| start + size);
+                        let next = exports[i + 1..].iter().find(|&&(next, _)| next > address);
+                        match next {
+                            Some(&(next, _)) if next < section_end => next,
+                            _ => section_end,
+                        }
+                    }
+                };
+                FuncInfo::name_only(address, end - address, name.to_string())
+            })
+            .collect();
+
+        Ok(FileInfo::finish(
+            Interner::default(),
+            func_infos,
+            Source::Exports,
+        ))
     }
 
-    fn build_file_info_macho(data: &[u8]) -> Result<FileInfo> {
+    fn build_file_info_macho(data: &[u8], keep_inlinees: bool) -> Result<FileInfo> {
         // On Mac, debug info is typically stored in `.dSYM` directories. But
         // they aren't normally built for Firefox because doing so is slow.
         // Instead, we read the symbol table of the given file, which has
@@ -494,10 +1114,24 @@ impl Fixer {
         //
         // We stop if any errors are encountered. The code could be made more
         // robust in the face of errors if necessary.
+        //
+        // If that fails or finds nothing (e.g. for system frameworks, which
+        // have no OSO entries) we fall back to function names from the symbol
+        // table, which is what `atos` does in that case.
 
         let macho = Fixer::macho(data)?;
+        match Fixer::build_file_info_macho_oso(&macho, keep_inlinees) {
+            Ok(file_info) if !file_info.func_infos.is_empty() => Ok(file_info),
+            res => match Fixer::build_file_info_macho_symtab(&macho) {
+                Ok(file_info) if !file_info.func_infos.is_empty() => Ok(file_info),
+                _ => res,
+            },
+        }
+    }
+
+    fn build_file_info_macho_oso(macho: &mach::MachO, keep_inlinees: bool) -> Result<FileInfo> {
         let arch = macho.header.cpuarch();
-        let sym_func_addrs = Fixer::sym_func_addrs(&macho)?;
+        let sym_func_addrs = Fixer::sym_func_addrs(macho)?;
 
         // Iterate again through the symbol table, reading every object file
         // that is referenced, and adjusting the addresses in those files using
@@ -529,6 +1163,7 @@ impl Fixer {
                                 &mut interner,
                                 &mut func_infos,
                                 arch,
+                                keep_inlinees,
                             )?;
                         }
                     }
@@ -545,15 +1180,153 @@ impl Fixer {
                         &mut interner,
                         &mut func_infos,
                         arch,
+                        keep_inlinees,
                     )?;
                 }
             }
         }
 
-        Ok(FileInfo::finish(interner, func_infos))
+        Ok(FileInfo::finish(interner, func_infos, Source::Oso))
+    }
+
+    /// Build a `FileInfo` containing function names but no line info, using
+    /// function boundaries from `LC_FUNCTION_STARTS` and names from the
+    /// non-stab entries in the symbol table.
+    fn build_file_info_macho_symtab(macho: &mach::MachO) -> Result<FileInfo> {
+        let object_load_address = Fixer::object_load_address(macho);
+        let text_range = Fixer::macho_text_range(macho, object_load_address);
+        let in_text = |address| match text_range {
+            Some((start, end)) => start <= address && address < end,
+            None => true,
+        };
+
+        // Get the names of all the symbols defined in `__text`. Several
+        // symbols can share an address, in which case we prefer global ones
+        // over local ones (such as `ltmp` labels).
+        let mut names: FxHashMap<u64, (&str, bool)> = FxHashMap::default();
+        for sym in macho.symbols() {
+            let (name, nlist) = sym.context("read symbol table from")?;
+            if nlist.is_stab() || nlist.get_type() != mach::symbols::N_SECT {
+                continue;
+            }
+            let address = nlist.n_value.wrapping_sub(object_load_address);
+            if !in_text(address) {
+                continue;
+            }
+            let name = name.strip_prefix('_').unwrap_or(name);
+            let is_global = nlist.is_global();
+            match names.entry(address) {
+                Entry::Occupied(mut o) => {
+                    if is_global && !o.get().1 {
+                        o.insert((name, is_global));
+                    }
+                }
+                Entry::Vacant(v) => {
+                    v.insert((name, is_global));
+                }
+            }
+        }
+
+        // If there is no `LC_FUNCTION_STARTS`, treat every symbol as the start
+        // of a function.
+        let mut starts = Fixer::macho_function_starts(macho)?;
+        if starts.is_empty() {
+            starts = names.keys().copied().collect();
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        // Each function extends to the start of the next one, or to the end
+        // of `__text` for the last one.
+        let text_end = text_range.map_or(0, |(_, end)| end);
+        let func_infos = starts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &start)| {
+                let (name, _) = names.get(&start)?;
+                let end = starts.get(i + 1).copied().unwrap_or(text_end);
+                let size = end.saturating_sub(start);
+                Some(FuncInfo::name_only(start, size, name.to_string()))
+            })
+            .collect();
+
+        Ok(FileInfo::finish(
+            Interner::default(),
+            func_infos,
+            Source::SymbolTable,
+        ))
+    }
+
+    /// Decode the `LC_FUNCTION_STARTS` data, if present, into a list of
+    /// function addresses relative to the object load address.
+    fn macho_function_starts(macho: &mach::MachO) -> Result<Vec<u64>> {
+        use mach::load_command::CommandVariant;
+
+        let cmd = macho.load_commands.iter().find_map(|lc| match lc.command {
+            CommandVariant::FunctionStarts(cmd) => Some(cmd),
+            _ => None,
+        });
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => return Ok(vec![]),
+        };
+
+        // The data lives in `__LINKEDIT`, whose contents we have in memory.
+        let linkedit = macho
+            .segments
+            .iter()
+            .find(|seg| seg.name().ok() == Some("__LINKEDIT"))
+            .context("find __LINKEDIT segment in")?;
+        let start = (cmd.dataoff as u64)
+            .checked_sub(linkedit.fileoff)
+            .context("read LC_FUNCTION_STARTS from")? as usize;
+        let data = linkedit
+            .data
+            .get(start..start + cmd.datasize as usize)
+            .context("read LC_FUNCTION_STARTS from")?;
+
+        // The data is a zero-terminated sequence of ULEB128 deltas, the first
+        // of which is relative to the start of `__TEXT`.
+        let mut starts = vec![];
+        let mut address = 0u64;
+        let mut delta = 0u64;
+        let mut shift = 0;
+        for &byte in data {
+            if shift < 64 {
+                delta |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if delta == 0 {
+                    break;
+                }
+                address = address.wrapping_add(delta);
+                starts.push(address);
+                delta = 0;
+                shift = 0;
+            }
+        }
+        Ok(starts)
+    }
+
+    /// Get the bounds of the `__TEXT,__text` section, relative to the object
+    /// load address.
+    fn macho_text_range(macho: &mach::MachO, object_load_address: u64) -> Option<(u64, u64)> {
+        for seg in macho.segments.iter() {
+            if seg.name().ok() != Some("__TEXT") {
+                continue;
+            }
+            for (section, _) in seg.sections().ok()? {
+                if section.name().ok()? == "__text" {
+                    let start = section.addr.wrapping_sub(object_load_address);
+                    return Some((start, start + section.size));
+                }
+            }
+        }
+        None
     }
 
-    fn macho(data: &[u8]) -> Result<mach::MachO> {
+    fn macho(data: &[u8]) -> Result<mach::MachO<'_>> {
         let mach = mach::Mach::parse(data).context("parse (with goblin)")?;
         match mach {
             mach::Mach::Binary(macho) => Ok(macho),
@@ -675,47 +1448,6 @@ impl Fixer {
         }
     }
 
-    /// Strip any annoying Firefox Breakpad junk from this filename. E.g.
-    /// `hg:hg.mozilla.org/integration/autoland:caps/BasePrincipal.cpp:04c31e994f29e72dd81a7340100d12f67e48a5b4`
-    /// becomes `caps/BasePrincipal.cpp`.
-    ///
-    /// It's not perfect, e.g. it will fail if the filename contains a colon.
-    /// But that should happen almost never, and if it does the junk won't be
-    /// stripped, which is still a reasonable outcome.
-    fn strip_firefox_breakpad_junk(file_name: &str) -> Option<&str> {
-        // Split on the colons.
-        let mut iter = file_name.split(':');
-
-        // Is the first element "hg"?
-        let s1 = iter.next()?;
-        if s1 != "hg" {
-            return None;
-        }
-
-        // Does the second element start with "hg.mozilla.org"?
-        let s2 = iter.next()?;
-        if !s2.starts_with("hg.mozilla.org") {
-            return None;
-        }
-
-        // The third element is the one we want.
-        let s3 = iter.next()?;
-
-        // Is the fourth element a hex id of length 40?
-        let s4 = iter.next()?;
-        if s4.len() != 40 || !s4.chars().all(|c| c.is_ascii_hexdigit()) {
-            return None;
-        }
-
-        // Is there no fifth element?
-        if iter.next().is_some() {
-            return None;
-        }
-
-        // It's a match. Return the interesting part.
-        Some(s3)
-    }
-
     /// Read the debug info from a file referenced by an OSO entry in a Macho-O
     /// symbol table.
     fn do_macho_oso(
@@ -725,6 +1457,7 @@ impl Fixer {
         interner: &mut Interner,
         func_infos: &mut Vec<FuncInfo>,
         arch: Arch,
+        keep_inlinees: bool,
     ) -> Result<()> {
         // Although we use `goblin` to iterate through the symbol
         // table, we use `symbolic` to read the debug info from the
@@ -760,6 +1493,7 @@ impl Fixer {
             debug_session,
             interner,
             func_infos,
+            keep_inlinees,
         );
 
         Ok(())
@@ -767,119 +1501,1205 @@ impl Fixer {
 
     /// Remap the path with local options' path.
     #[inline]
-    fn remap(&self, in_file_name: &str) -> Option<String> {
-        if let Some(local_info) = &self.local_info {
-            if let Some(file_name) = Path::new(in_file_name).file_name() {
-                if let Some(new_path) = Path::new(&local_info.local_dir).join(file_name).to_str() {
-                    if fs::metadata(new_path).is_ok() {
-                        return Some(new_path.to_string());
-                    }
-                }
+    fn remap(&mut self, in_file_name: &str) -> Option<String> {
+        if let Some((apk_file, entry_name)) = apk::split_path(in_file_name) {
+            if let Some(new_path) = self.apk_remaps.get(in_file_name) {
+                return new_path.clone();
+            }
+            let new_path = self.remap_apk_entry(apk_file, entry_name);
+            self.apk_remaps
+                .insert(in_file_name.to_string(), new_path.clone());
+            if new_path.is_some() {
+                return new_path;
             }
         }
-        None
+        let build_id = Path::new(in_file_name)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| self.module_ids.get(file_name))
+            .cloned();
+        self.local_path(in_file_name, build_id.as_ref())
     }
 
-    /// Fix stack frames within `line` as necessary. Prints any errors to stderr.
-    #[inline]
-    fn fix(&mut self, line: String) -> String {
-        // Apply the regexp.
-        let captures = if let Some(captures) = self.re.captures(&line) {
-            captures
+    /// Find a file with the same file name as `in_file_name`, and the given
+    /// build ID (if any), in the local directories.
+    fn local_path(&mut self, in_file_name: &str, build_id: Option<&BuildId>) -> Option<String> {
+        let local_index = self.local_index.as_mut()?;
+        let local_path = local_index.find(in_file_name, build_id);
+        for warning in local_index.take_mismatches() {
+            diag::warning(self.level, warning);
+            self.stats.mismatches += 1;
+        }
+        local_path
+    }
+
+    /// Remap a library within an APK. The APK itself may be found in the
+    /// local directories. If the local directories contain a copy of the
+    /// library with the same build ID (typically an unstripped copy from the
+    /// objdir) that is used, otherwise the library is read from the APK.
+    fn remap_apk_entry(&mut self, apk_file: &str, entry_name: &str) -> Option<String> {
+        let apk_file = if fs::metadata(apk_file).is_ok() {
+            apk_file.to_string()
         } else {
-            return line;
+            self.local_path(apk_file, None)?
         };
 
-        let before = &captures[1];
-        let in_func_name = &captures[2];
-        let in_file_name = &captures[3];
-        let address = u64::from_str_radix(&captures[4], 16).unwrap();
-        let after = &captures[5];
-
-        // In JSON mode, unescape the function name before using it for
-        // lookups, error messages, etc.
-        let raw_in_file_name = if let JsonMode::Yes = self.json_mode {
-            Fixer::json_unescape(in_file_name)
-        } else if fs::metadata(in_file_name).is_ok() {
-            in_file_name.to_string()
-        } else if let Some(new_path) = self.remap(in_file_name) {
-            new_path
-        } else {
-            // File is not found, but use original path.
-            in_file_name.to_string()
-        };
-
-        // If we haven't seen this file yet, parse and record its contents, for
-        // this lookup and any future lookups.
-        let file_info = match self.file_infos.entry(raw_in_file_name.to_string()) {
-            Entry::Occupied(o) => o.into_mut(),
-            Entry::Vacant(v) => {
-                match Fixer::build_file_info(&raw_in_file_name, &self.bp_info) {
-                    Ok(file_info) => v.insert(file_info),
-                    Err(err) => {
-                        // Print an error message and then set up an empty
-                        // `FileInfo` for this file, for two reasons.
-                        // - If an invalid file is mentioned multiple times in the
-                        //   input, an error message will be issued only on the
-                        //   first occurrence.
-                        // - The line will still receive some transformation, using
-                        //   the "no symbols or debug info" case below.
-                        eprintln!(
-                            "fix-stacks: error: failed to {} `{}`",
-                            err, raw_in_file_name
-                        );
-                        err.chain()
-                            .skip(1)
-                            .for_each(|cause| eprintln!("fix-stacks: {}", cause));
-
-                        v.insert(FileInfo::default())
-                    }
+        if self.local_index.is_some() {
+            if let Some((_, build_id)) = apk::read_entry_ids(&apk_file, entry_name) {
+                if let Some(local_path) =
+                    self.local_path(entry_name, Some(&BuildId::Debug(build_id)))
+                {
+                    return Some(local_path);
                 }
             }
-        };
+        }
 
-        // In JSON mode, we need to escape any new strings we produce. However,
-        // strings from the input (i.e. `in_func_name` and `in_file_name`),
-        // will already be escaped, so if they are used in the output they
-        // shouldn't be re-escaped.
-        if let Some(func_info) = file_info.func_info(address) {
-            let raw_out_func_name = func_info.demangled_name();
-            let out_func_name = if let JsonMode::Yes = self.json_mode {
-                Fixer::json_escape(&raw_out_func_name)
-            } else {
-                raw_out_func_name
-            };
+        Some(format!("{}!/{}", apk_file, entry_name))
+    }
 
-            if let Some(line_info) = func_info.line_info(address) {
-                // We have the function name, filename, and line number from
-                // the debug info.
-                let raw_out_file_name = file_info.interner.get(line_info.path);
-                let out_file_name_str;
-                let mut out_file_name = if let JsonMode::Yes = self.json_mode {
-                    out_file_name_str = Fixer::json_escape(raw_out_file_name);
-                    &out_file_name_str
-                } else {
-                    raw_out_file_name
+    /// Find the library within an APK that contains `offset`, which is an
+    /// offset into the APK. Return the library's path, in `<apk>!/<entry>`
+    /// form, and the address within the library that the offset maps to.
+    fn apk_entry_at(&mut self, apk_file: &str, offset: u64) -> Option<(String, u64)> {
+        let entries = self
+            .apk_entries
+            .entry(apk_file.to_string())
+            .or_insert_with(|| apk::read_entries(apk_file).unwrap_or_default());
+        let entry = apk::entry_at_offset(entries, offset)?;
+        let entry_offset = offset - entry.data_start;
+        let entry_path = format!("{}!/{}", apk_file, entry.name);
+        let address = self.file_offset_to_address(&entry_path, entry_offset);
+        Some((entry_path, address))
+    }
+
+    /// Convert an offset within `bin_file` to an address, as used in its
+    /// debug info. Only the ELF headers are read, in place for a library
+    /// within an APK.
+    fn file_offset_to_address(&mut self, bin_file: &str, file_offset: u64) -> u64 {
+        let segments = self
+            .elf_segments
+            .entry(bin_file.to_string())
+            .or_insert_with(|| {
+                let (path, base) = match apk::split_path(bin_file) {
+                    Some((apk_file, entry_name)) => {
+                        match apk::entry_data_start(apk_file, entry_name) {
+                            Some(data_start) => (apk_file, data_start),
+                            None => return Segments::default(),
+                        }
+                    }
+                    None => (bin_file, 0),
                 };
+                match fs::File::open(path) {
+                    Ok(mut file) => Segments::read(&mut file, base),
+                    Err(_) => Segments::default(),
+                }
+            });
+        segments.address(file_offset)
+    }
+
+    /// If we haven't seen this file yet, parse and record its contents, for
+    /// this lookup and any future lookups.
+    fn load_file_info(&mut self, raw_in_file_name: &str) {
+        if let Entry::Vacant(v) = self.file_infos.entry(raw_in_file_name.to_string()) {
+            let start = Instant::now();
+            match Fixer::build_file_info(
+                raw_in_file_name,
+                &self.bp_info,
+                &self.pdb_info,
+                self.keep_inlinees,
+            ) {
+                Ok(mut file_info) => {
+                    if let Some(err) = file_info.mismatch.take() {
+                        diag::file_error(self.level, &err, raw_in_file_name);
+                        self.stats.mismatches += 1;
+                    }
+
+                    // Paths with version control info are left alone.
+                    let path_map = &self.path_map;
+                    let vcs_schemes = &self.vcs_schemes;
+                    file_info
+                        .interner
+                        .remap(|path| match VcsPath::parse(path, vcs_schemes) {
+                            Some(_) => None,
+                            None => path_map.map(path),
+                        });
+                    diag::verbose(
+                        self.level,
+                        format!(
+                            "loaded `{}` in {:.1?}: {} functions from {}",
+                            raw_in_file_name,
+                            start.elapsed(),
+                            file_info.func_infos.len(),
+                            file_info.source
+                        ),
+                    );
+                    v.insert(file_info);
+                }
+                Err(err) => {
+                    // Print an error message and then set up an empty
+                    // `FileInfo` for this file, for two reasons.
+                    // - If an invalid file is mentioned multiple times in the
+                    //   input, an error message will be issued only on the
+                    //   first occurrence.
+                    // - The line will still receive some transformation, using
+                    //   the "no symbols or debug info" case in `fix`.
+                    diag::file_error(self.level, &err, raw_in_file_name);
+                    if err.chain().any(|cause| cause.is::<IdMismatch>()) {
+                        self.stats.mismatches += 1;
+                    }
+                    self.stats
+                        .record_failure(raw_in_file_name, format!("failed to {:#}", err));
+
+                    v.insert(FileInfo::default());
+                }
+            }
+        }
+    }
+
+    /// Get the location of a line within a source file, as it should appear
+    /// in the output (before any JSON escaping).
+    fn raw_location(&self, raw_file_name: &str, line: u32, source: &Source) -> String {
+        // Maybe turn the location into a link, or strip some junk, for
+        // Breakpad and srcsrv file names. The path within a version control
+        // location isn't mapped when loaded, so it's done here instead.
+        if self.bp_info.is_some() || self.pdb_info.srcsrv {
+            if let Some(vcs_path) = VcsPath::parse(raw_file_name, &self.vcs_schemes) {
+                if let Some(source_link_info) = &self.source_link_info {
+                    return source_link_info.location(&vcs_path, line.into());
+                }
+                // Without a link, PDB paths from `--srcsrv` keep their
+                // revision, as `path@rev`, so the right version of the file
+                // can still be found. Breakpad paths have always been printed
+                // without it.
+                let path = self.path_map.map(vcs_path.path);
+                let path = path.as_deref().unwrap_or(vcs_path.path);
+                return match source {
+                    Source::Pdb(_) if !vcs_path.rev.is_empty() => {
+                        format!("{}@{}:{}", path, vcs_path.rev, line)
+                    }
+                    _ => format!("{}:{}", path, line),
+                };
+            }
+        }
+        format!("{}:{}", raw_file_name, line)
+    }
+
+    /// Find the library for a frame in an Android tombstone. It may be at the
+    /// path given, in the local directories, or in a build ID directory. If
+    /// the frame has a build ID, only a library with that build ID is used.
+    fn resolve_tombstone_file(&mut self, path: &str, build_id: Option<&str>) -> Option<String> {
+        let build_id = build_id.map(BuildId::code);
+        let key = (path.to_string(), build_id.clone());
+        if let Some(resolved) = self.tombstone_files.get(&key) {
+            return resolved.clone();
+        }
+
+        // A library loaded from an APK may be named like `base.apk!libxul.so`.
+        let lib_name = match path.rsplit_once('!') {
+            Some((_, lib_name)) if !lib_name.starts_with('/') => lib_name,
+            _ => path,
+        };
 
-                // Maybe strip some junk from Breakpad file names.
-                if self.bp_info.is_some() {
-                    if let Some(stripped) = Fixer::strip_firefox_breakpad_junk(out_file_name) {
-                        out_file_name = stripped
+        // A library within an APK, in `<apk>!/<entry>` form, is checked
+        // using just its headers.
+        let apk_entry = apk::split_path(path);
+        let resolved = match &build_id {
+            None => Some(if fs::metadata(path).is_ok() || apk_entry.is_some() {
+                path.to_string()
+            } else {
+                self.local_path(lib_name, None)
+                    .unwrap_or_else(|| path.to_string())
+            }),
+            Some(build_id) => {
+                let mut resolved = None;
+                if fs::metadata(path).is_ok() || apk_entry.is_some() {
+                    let verified = match apk_entry {
+                        Some((apk_file, entry_name)) => local::verify_ids(
+                            Path::new(path),
+                            &apk::read_entry_ids(apk_file, entry_name),
+                            build_id,
+                        ),
+                        None => local::verify(Path::new(path), build_id),
+                    };
+                    match verified {
+                        Ok(()) => resolved = Some(path.to_string()),
+                        Err(warning) => {
+                            diag::warning(self.level, warning);
+                            self.stats.mismatches += 1;
+                        }
                     }
+                }
+                if resolved.is_none() {
+                    resolved = self.local_path(lib_name, Some(build_id));
+                }
+                if let (None, BuildId::Code(code_id)) = (&resolved, build_id) {
+                    if code_id.len() > 2 {
+                        let (dir, file) = code_id.split_at(2);
+                        resolved = self
+                            .build_id_dirs
+                            .iter()
+                            .flat_map(|build_id_dir| {
+                                [format!("{}.debug", file), file.to_string()]
+                                    .map(|file| build_id_dir.join(dir).join(file))
+                            })
+                            .find(|candidate| fs::metadata(candidate).is_ok())
+                            .and_then(|candidate| candidate.to_str().map(str::to_string));
+                    }
+                }
+                if resolved.is_none() {
+                    diag::error(
+                        self.level,
+                        format!("failed to find `{}` with build ID `{}`", path, build_id),
+                    );
+                    self.stats.record_failure(
+                        path,
+                        format!("failed to find it with build ID `{}`", build_id),
+                    );
+                }
+                resolved
+            }
+        };
+        self.tombstone_files.insert(key, resolved.clone());
+        resolved
+    }
+
+    /// In a tombstone, a library loaded directly from an APK is named by the
+    /// APK, with an `(offset 0x...)` annotation giving the offset of its
+    /// mapping within the APK. Find the library, in `<apk>!/<entry>` form, or
+    /// a local copy of it. The address is already relative to the library.
+    fn tombstone_apk_entry(&mut self, path: &str, offset: &str) -> Option<String> {
+        if !apk::is_apk(path) {
+            return None;
+        }
+        let offset = offset.strip_prefix(" (offset 0x")?.strip_suffix(')')?;
+        let offset = u64::from_str_radix(offset, 16).ok()?;
+        let apk_file = if fs::metadata(path).is_ok() {
+            path.to_string()
+        } else {
+            self.local_path(path, None)?
+        };
+        // The address is already relative to the library, so only the
+        // library is needed.
+        let (entry_path, _) = self.apk_entry_at(&apk_file, offset)?;
+        Some(self.remap(&entry_path).unwrap_or(entry_path))
+    }
+
+    /// Fix an Android tombstone stack frame within `line`, if there is one.
+    /// The frame is fixed in place, by replacing the function name (if
+    /// present) with the one from the debug info, and adding the source
+    /// location after it.
+    fn fix_tombstone(&mut self, line: String) -> String {
+        let frame = if let Some(frame) = self.tombstone_parser.parse(&line) {
+            frame
+        } else {
+            return line;
+        };
+        let address = match u64::from_str_radix(frame.pc, 16) {
+            Ok(address) => address,
+            Err(_) => {
+                self.warn_malformed(&line, "address too large");
+                self.push_unresolved(None, Some(frame.path), frame.pc);
+                self.stats.record(Resolution::Unresolved);
+                return line;
+            }
+        };
+        // In JSON mode, unescape the path before using it for lookups.
+        let path = if let JsonMode::Yes = self.json_mode {
+            match Fixer::json_unescape(frame.path) {
+                Some(path) => path,
+                None => {
+                    self.warn_malformed(&line, "bad JSON escape");
+                    self.push_unresolved(None, Some(frame.path), frame.pc);
+                    self.stats.record(Resolution::Unresolved);
+                    return line;
+                }
+            }
+        } else {
+            frame.path.to_string()
+        };
+        let path = self
+            .tombstone_apk_entry(&path, frame.offset)
+            .unwrap_or(path);
+        let raw_in_file_name = match self.resolve_tombstone_file(&path, frame.build_id) {
+            Some(raw_in_file_name) => raw_in_file_name,
+            None => {
+                self.push_unresolved(None, Some(frame.path), frame.pc);
+                self.stats.record(Resolution::Unresolved);
+                return line;
+            }
+        };
+
+        self.load_file_info(&raw_in_file_name);
+        let file_info = &self.file_infos[&raw_in_file_name];
+
+        let func_info = if let Some(func_info) = file_info.func_info(address) {
+            func_info
+        } else {
+            self.push_unresolved(None, Some(frame.path), frame.pc);
+            self.stats.record(Resolution::Unresolved);
+            return line;
+        };
+        if let Some(signature) = &mut self.signature {
+            signature.push(signature::normalize(&func_info.mangled_name));
+        }
+
+        // As in `fix`, new strings must be escaped in JSON mode, but strings
+        // from the input must not be.
+        let escape = |string: String| match self.json_mode {
+            JsonMode::Yes => Fixer::json_escape(&string),
+            JsonMode::No => string,
+        };
+        let mut out = format!(
+            "{}{}{}{}{} ({})",
+            frame.before,
+            frame.pc,
+            frame.sep,
+            frame.path,
+            frame.offset,
+            escape(func_info.demangled_name(&self.name_options))
+        );
+        if let Some(line_info) = func_info.line_info(address) {
+            let location = escape(self.raw_location(
+                file_info.interner.get(line_info.path),
+                line_info.line,
+                &file_info.source,
+            ));
+            let (lb, rb) = self.brackets(&file_info.source);
+            out.push_str(&format!(" {}{}{}", lb, location, rb));
+            self.stats.record(Resolution::Fixed);
+        } else {
+            self.stats.record(Resolution::NameOnly);
+        }
+        out.push_str(&self.source_annotation(&file_info.source));
+        out.push_str(frame.build_id_part);
+        out.push_str(frame.after);
+        out
+    }
+
+    /// Fix a line of `perf script` output. Samples and mmap events are
+    /// recorded, so that the absolute addresses in the following frames can
+    /// be converted to addresses within their modules.
+    ///
+    /// A frame is rewritten as `<address> <function> (<module>)`, like the
+    /// input, so the output can still be folded by flamegraph's
+    /// `stackcollapse-perf.pl`. If the address is within inlined code, one
+    /// frame is printed per inlined function, innermost first, all with the
+    /// same address. Frames that can't be resolved are left unchanged.
+    fn fix_perf(&mut self, line: String) -> String {
+        let perf_info = self.perf_info.as_mut().unwrap();
+        let (before, address, dso) = match perf_info.parser.parse(&line) {
+            Some(PerfLine::Sample { pid }) => {
+                perf_info.set_pid(pid);
+                return line;
+            }
+            Some(PerfLine::Mmap { pid, mapping }) => {
+                perf_info.add_mmap(pid, mapping);
+                return line;
+            }
+            Some(PerfLine::Frame {
+                before,
+                address,
+                dso,
+            }) => (before, address, dso),
+            None => return line,
+        };
+
+        // Frames in JIT code have absolute addresses, and perf names their
+        // module after the perf map or jitdump file, or `[unknown]`.
+        let is_jit = !self.jit_info.jit_files.is_empty()
+            && (dso == "[unknown]"
+                || self
+                    .jit_info
+                    .jit_files
+                    .iter()
+                    .any(|jit_file| jit_file == dso));
+        let (file_info, lookup_address) = if is_jit {
+            (&self.jit_info.file_info, address)
+        } else {
+            let file_offset = perf_info
+                .mapping(address, dso)
+                .and_then(|mapping| (address - mapping.start).checked_add(mapping.file_offset));
+            let file_offset = match file_offset {
+                Some(file_offset) => file_offset,
+                None => {
+                    self.stats.record(Resolution::Unresolved);
+                    return line;
+                }
+            };
+            let raw_in_file_name = if fs::metadata(dso).is_ok() {
+                dso.to_string()
+            } else if let Some(new_path) = self.remap(dso) {
+                new_path
+            } else {
+                dso.to_string()
+            };
+            let lookup_address = self.file_offset_to_address(&raw_in_file_name, file_offset);
+            self.load_file_info(&raw_in_file_name);
+            (&self.file_infos[&raw_in_file_name], lookup_address)
+        };
+
+        let func_info = match file_info.func_info(lookup_address) {
+            Some(func_info) => func_info,
+            None => {
+                self.stats.record(Resolution::Unresolved);
+                return line;
+            }
+        };
+        self.stats
+            .record(if func_info.line_info(lookup_address).is_some() {
+                Resolution::Fixed
+            } else {
+                Resolution::NameOnly
+            });
+        let frames: Vec<_> = func_info
+            .inline_chain(lookup_address)
+            .iter()
+            .rev()
+            .map(|func_info| {
+                format!(
+                    "{}{:x} {} ({})",
+                    before,
+                    address,
+                    func_info.demangled_name(&self.name_options),
+                    dso
+                )
+            })
+            .collect();
+        frames.join("\n")
+    }
+
+    /// Find and load the debug info for a module named in a symbolication
+    /// request, preferring Breakpad symbols over local binaries. Returns the
+    /// key of its `FileInfo` in `file_infos`.
+    fn load_module(&mut self, debug_name: &str, debug_id: DebugId) -> Option<String> {
+        // The name comes from the request, so it mustn't be able to escape
+        // the directories.
+        if debug_name.contains(['/', '\\']) || debug_name == ".." {
+            return None;
+        }
+        let mut bin_file = None;
+        if let Some(BreakpadInfo { syms_dir }) = &self.bp_info {
+            // E.g. `syms/xul.pdb/<uuid>/xul.sym` or
+            // `syms/libxul.so/<uuid>/libxul.so.sym`.
+            let sym_seg = match debug_name.strip_suffix(".pdb") {
+                Some(base) => format!("{}.sym", base),
+                None => format!("{}.sym", debug_name),
+            };
+            let sym_file: PathBuf = [
+                syms_dir,
+                debug_name,
+                &debug_id.breakpad().to_string(),
+                &sym_seg,
+            ]
+            .iter()
+            .collect();
+            if sym_file.is_file() {
+                bin_file = Some(sym_file.display().to_string());
+            }
+        }
+        if bin_file.is_none() {
+            // A PDB's debug ID is also that of its PE file, which is found by
+            // build ID if the PDB isn't in the local directories.
+            bin_file = self.local_path(debug_name, Some(&BuildId::Debug(debug_id)));
+        }
+        let bin_file = bin_file?;
+        self.load_file_info(&bin_file);
+        if self.file_infos[&bin_file].func_infos.is_empty() {
+            return None;
+        }
+        Some(bin_file)
+    }
+
+    /// Process a request to the Firefox Profiler's symbolication API, which
+    /// looks like this:
+    ///
+    /// ```text
+    /// {"jobs": [{"memoryMap": [["xul.pdb", "<debug ID>"], ...],
+    ///            "stacks": [[[<module index>, <offset>], ...], ...]}]}
+    /// ```
+    ///
+    /// A single job without the `jobs` wrapper is also accepted. The result
+    /// has one entry per job, giving the function, function offset, file,
+    /// line and inlined functions of each frame.
+    fn symbolicate_v5(&mut self, request: &Value) -> Result<Value> {
+        let jobs = match request.get("jobs") {
+            Some(jobs) => jobs.as_array().context("read `jobs`")?.clone(),
+            None => vec![request.clone()],
+        };
+        let mut results = vec![];
+        for job in &jobs {
+            let memory_map = job
+                .get("memoryMap")
+                .and_then(Value::as_array)
+                .context("read `memoryMap`")?;
+            let mut modules = vec![];
+            let mut found_modules = serde_json::Map::new();
+            for module in memory_map {
+                let (debug_name, debug_id) = match module.as_array().map(Vec::as_slice) {
+                    Some([Value::String(name), Value::String(id)]) => (name, id),
+                    _ => bail!("read `memoryMap` entry `{}`", module),
+                };
+                let file_key = DebugId::from_breakpad(debug_id)
+                    .ok()
+                    .and_then(|debug_id| self.load_module(debug_name, debug_id));
+                found_modules.insert(
+                    format!("{}/{}", debug_name, debug_id),
+                    Value::Bool(file_key.is_some()),
+                );
+                modules.push((debug_name.as_str(), file_key));
+            }
+
+            let stacks = job
+                .get("stacks")
+                .and_then(Value::as_array)
+                .context("read `stacks`")?;
+            let mut out_stacks = vec![];
+            for stack in stacks {
+                let stack = stack.as_array().context("read stack")?;
+                let mut out_stack = vec![];
+                for (index, frame) in stack.iter().enumerate() {
+                    let (module_index, address) = match frame.as_array().map(Vec::as_slice) {
+                        Some([module_index, address]) => (
+                            module_index.as_i64().context("read module index")?,
+                            address.as_u64().context("read module offset")?,
+                        ),
+                        _ => bail!("read frame `{}`", frame),
+                    };
+                    let mut out_frame = json!({
+                        "frame": index,
+                        "module_offset": format!("0x{:x}", address),
+                    });
+                    let module = usize::try_from(module_index)
+                        .ok()
+                        .and_then(|module_index| modules.get(module_index));
+                    let mut resolution = Resolution::Unresolved;
+                    if let Some((debug_name, file_key)) = module {
+                        out_frame["module"] = json!(debug_name);
+                        if let Some(file_key) = file_key {
+                            resolution = self.symbolicate_frame(file_key, address, &mut out_frame);
+                        }
+                    }
+                    self.stats.record(resolution);
+                    out_stack.push(out_frame);
+                }
+                out_stacks.push(Value::Array(out_stack));
+            }
+            results.push(json!({
+                "stacks": out_stacks,
+                "found_modules": found_modules,
+            }));
+        }
+        Ok(json!({ "results": results }))
+    }
+
+    /// Add the function, file, line, inlined functions and symbol source at
+    /// `address` in the file with the key `file_key` to a frame of a
+    /// symbolication result, and return how much was found.
+    /// File names are not altered, so the profiler can find the source of
+    /// files with version control paths.
+    fn symbolicate_frame(&self, file_key: &str, address: u64, out_frame: &mut Value) -> Resolution {
+        let file_info = &self.file_infos[file_key];
+        let func_info = match file_info.func_info(address) {
+            Some(func_info) => func_info,
+            None => return Resolution::Unresolved,
+        };
+        // The file and line of each function is the location of the call to
+        // the function inlined into it, if any.
+        let mut locations = func_info
+            .inline_chain(address)
+            .into_iter()
+            .map(|func_info| {
+                let mut location =
+                    json!({ "function": func_info.demangled_name(&self.name_options) });
+                if let Some(line_info) = func_info.line_info(address) {
+                    location["file"] = json!(file_info.interner.get(line_info.path));
+                    location["line"] = json!(line_info.line);
+                }
+                location
+            })
+            .collect::<Vec<_>>();
+        let outer = locations.remove(0);
+        let resolution = if outer.get("line").is_some() {
+            Resolution::Fixed
+        } else {
+            Resolution::NameOnly
+        };
+        for (key, value) in outer.as_object().unwrap() {
+            out_frame[key] = value.clone();
+        }
+        out_frame["function_offset"] = json!(format!("0x{:x}", address - func_info.address));
+        out_frame["symbol_source"] = file_info.source.to_json();
+        if !locations.is_empty() {
+            // The innermost function comes first.
+            locations.reverse();
+            out_frame["inlines"] = Value::Array(locations);
+        }
+        resolution
+    }
+
+    /// Symbolicate a profile in the Firefox Profiler's processed format. The
+    /// libraries are found in the same way as for `symbolicate_v5`.
+    fn symbolicate_profile(&mut self, profile: &mut Value) -> Result<()> {
+        let libs = profile::libs(profile)?;
+        let mut file_keys: Vec<Option<Option<String>>> = vec![None; libs.len()];
+        profile::symbolicate(profile, |lib, address| {
+            let file_key = match file_keys.get_mut(lib) {
+                Some(file_key) => file_key.get_or_insert_with(|| {
+                    let Lib {
+                        debug_name,
+                        breakpad_id,
+                    } = &libs[lib];
+                    DebugId::from_breakpad(breakpad_id)
+                        .ok()
+                        .and_then(|debug_id| self.load_module(debug_name, debug_id))
+                }),
+                None => &None,
+            };
+            let symbol = file_key.as_ref().and_then(|file_key| {
+                let file_info = &self.file_infos[file_key];
+                let func_info = file_info.func_info(address)?;
+                let line_info = func_info.line_info(address);
+                Some(Symbol {
+                    name: func_info.demangled_name(&self.name_options),
+                    file: line_info
+                        .map(|line_info| file_info.interner.get(line_info.path).to_string()),
+                    line: line_info.map(|line_info| line_info.line),
+                })
+            });
+            self.stats.record(match &symbol {
+                Some(Symbol { line: Some(_), .. }) => Resolution::Fixed,
+                Some(_) => Resolution::NameOnly,
+                None => Resolution::Unresolved,
+            });
+            symbol
+        })
+    }
+
+    /// Fix every frame in a DMD report, which has a table of frame
+    /// descriptions that are shared by the traces in the report:
+    ///
+    /// ```text
+    /// {..., "frameTable": {"A": "#00: ???[libxul.so +0x1234]", ...}}
+    /// ```
+    ///
+    /// Because the whole report is parsed, the frames are fixed as plain
+    /// text, without the escaping needed by `--json`.
+    fn fix_dmd(&mut self, report: &mut Value) -> Result<()> {
+        let frame_table = report
+            .get_mut("frameTable")
+            .and_then(Value::as_object_mut)
+            .context("read `frameTable` in")?;
+        let json_mode = std::mem::replace(&mut self.json_mode, JsonMode::No);
+        // Different records can have the same description, e.g. in reports
+        // merged from several processes.
+        let mut fixed: FxHashMap<String, String> = FxHashMap::default();
+        for frame in frame_table.values_mut() {
+            if let Value::String(frame) = frame {
+                *frame = match fixed.entry(std::mem::take(frame)) {
+                    Entry::Occupied(e) => e.get().clone(),
+                    Entry::Vacant(e) => {
+                        let out = self.fix(e.key().clone());
+                        e.insert(out).clone()
+                    }
+                };
+            }
+        }
+        self.json_mode = json_mode;
+        Ok(())
+    }
+
+    /// Fill in the frames without symbols in the output of
+    /// `minidump-stackwalk --json`, using local binaries. A frame looks like
+    /// this:
+    ///
+    /// ```text
+    /// {"frame": 3, "module": "libxul.so", "module_offset": "0x1234", ...}
+    /// ```
+    ///
+    /// and has `function`, `function_offset`, `file` and `line` fields added
+    /// if they are absent, along with a `symbol_source` field. The binary is
+    /// found by the module's file name and debug ID, which are in the
+    /// `modules` list.
+    fn fix_minidump_json(&mut self, report: &mut Value) -> Result<()> {
+        let mut modules: FxHashMap<String, Option<BuildId>> = FxHashMap::default();
+        for module in report["modules"].as_array().context("read `modules` in")? {
+            if let Some(file_name) = module["filename"].as_str() {
+                let debug_id = module["debug_id"]
+                    .as_str()
+                    .and_then(|debug_id| DebugId::from_breakpad(debug_id).ok());
+                modules.insert(file_name.to_string(), debug_id.map(BuildId::Debug));
+            }
+        }
+
+        // The crashing thread is duplicated outside `threads`.
+        let mut threads: Vec<&mut Value> = vec![];
+        for (key, value) in report
+            .as_object_mut()
+            .context("read `threads` in")?
+            .iter_mut()
+        {
+            match (key.as_str(), value) {
+                ("threads", Value::Array(report_threads)) => threads.extend(report_threads),
+                ("crashing_thread", thread) => threads.push(thread),
+                _ => {}
+            }
+        }
+        let mut bin_files: FxHashMap<String, Option<String>> = FxHashMap::default();
+        for thread in threads {
+            let frames = match thread.get_mut("frames").and_then(Value::as_array_mut) {
+                Some(frames) => frames,
+                None => continue,
+            };
+            for frame in frames.iter_mut() {
+                let has_function = matches!(frame.get("function"), Some(f) if !f.is_null());
+                let module = frame["module"].as_str();
+                let address = frame["module_offset"].as_str().and_then(|offset| {
+                    u64::from_str_radix(offset.trim_start_matches("0x"), 16).ok()
+                });
+                let (module, address) = match (module, address) {
+                    (Some(module), Some(address)) if !has_function => (module, address),
+                    _ => continue,
+                };
+                let bin_file = match bin_files.entry(module.to_string()) {
+                    Entry::Occupied(e) => e.get().clone(),
+                    Entry::Vacant(e) => {
+                        let build_id = modules.get(module).cloned().flatten();
+                        let bin_file = self.local_path(module, build_id.as_ref());
+                        e.insert(bin_file).clone()
+                    }
+                };
+                let bin_file = match bin_file {
+                    Some(bin_file) => bin_file,
+                    None => {
+                        self.stats.record(Resolution::Unresolved);
+                        continue;
+                    }
+                };
+                self.load_file_info(&bin_file);
+                let file_info = &self.file_infos[&bin_file];
+                let resolution = if let Some(func_info) = file_info.func_info(address) {
+                    frame["function"] = json!(func_info.demangled_name(&self.name_options));
+                    frame["function_offset"] =
+                        json!(format!("0x{:x}", address - func_info.address));
+                    frame["symbol_source"] = file_info.source.to_json();
+                    if let Some(line_info) = func_info.line_info(address) {
+                        frame["file"] = json!(file_info.interner.get(line_info.path));
+                        frame["line"] = json!(line_info.line);
+                        Resolution::Fixed
+                    } else {
+                        Resolution::NameOnly
+                    }
+                } else {
+                    Resolution::Unresolved
+                };
+                self.stats.record(resolution);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a request to the server started by `--serve`.
+    fn handle_request(&mut self, request: &Request) -> Response {
+        if request.path.trim_end_matches('/') != "/symbolicate/v5" {
+            return Response::error(404, &format!("unknown path `{}`", request.path));
+        }
+        if request.method != "POST" {
+            return Response::error(405, "expected a POST request");
+        }
+        let result = serde_json::from_slice(&request.body)
+            .context("parse request")
+            .and_then(|request| self.symbolicate_v5(&request));
+        match result {
+            Ok(response) => Response::new(200, response.to_string()),
+            Err(err) => Response::error(400, &format!("failed to {}", err)),
+        }
+    }
+
+    /// Get the name of the file to read for a frame whose file name in the
+    /// input is `in_file_name`. Returns `None` if the name has a bad JSON
+    /// escape.
+    fn frame_file_name(&mut self, in_file_name: &str) -> Option<String> {
+        // In JSON mode, unescape the function name before using it for
+        // lookups, error messages, etc.
+        if let JsonMode::Yes = self.json_mode {
+            Fixer::json_unescape(in_file_name)
+        } else if fs::metadata(in_file_name).is_ok() {
+            Some(in_file_name.to_string())
+        } else if let Some(new_path) = self.remap(in_file_name) {
+            Some(new_path)
+        } else {
+            // File is not found, but use original path.
+            Some(in_file_name.to_string())
+        }
+    }
+
+    /// Find and load the debug info for a frame at `address` in
+    /// `raw_in_file_name`. Returns the key of its `FileInfo` in `file_infos`
+    /// (or `None` for JIT code), and the address to look up in it.
+    fn frame_file_info(&mut self, raw_in_file_name: String, address: u64) -> (Option<String>, u64) {
+        // A library loaded directly from an APK may be identified just by the
+        // APK, with an address that is an offset into the APK. If so, find
+        // the library and the address within it.
+        let mut lookup_address = address;
+        let raw_in_file_name = if apk::is_apk(&raw_in_file_name) {
+            match self.apk_entry_at(&raw_in_file_name, address) {
+                Some((entry_path, entry_address)) => {
+                    lookup_address = entry_address;
+                    self.remap(&entry_path).unwrap_or(entry_path)
+                }
+                None => raw_in_file_name,
+            }
+        } else {
+            raw_in_file_name
+        };
+
+        // Frames in JIT code have an absolute address, and no file name or
+        // the name of a JIT file (as in `perf` output).
+        let is_jit = !self.jit_info.jit_files.is_empty()
+            && (raw_in_file_name == "???" || self.jit_info.jit_files.contains(&raw_in_file_name));
+        if is_jit {
+            (None, lookup_address)
+        } else {
+            self.load_file_info(&raw_in_file_name);
+            (Some(raw_in_file_name), lookup_address)
+        }
+    }
+
+    /// Get the `FileInfo` returned by `frame_file_info`.
+    fn file_info(&self, file_key: &Option<String>) -> &FileInfo {
+        match file_key {
+            Some(file_key) => &self.file_infos[file_key],
+            None => &self.jit_info.file_info,
+        }
+    }
+
+    /// Fix a frame matched by the frame pattern from the config file at
+    /// `index`, whose captures are in `values`.
+    fn fix_configured(
+        &mut self,
+        line: String,
+        index: usize,
+        mut values: FxHashMap<&str, String>,
+    ) -> String {
+        let parse_hex = |hex: &str| u64::from_str_radix(hex.trim_start_matches("0x"), 16);
+        let (file_key, address, lookup_address) = if let Some(address) = values.get("address") {
+            match parse_hex(address) {
+                Ok(address) => (None, address, address),
+                Err(_) => {
+                    self.warn_malformed(&line, "bad address");
+                    let in_func_name = values.get("func").map(String::as_str);
+                    self.push_unresolved(in_func_name, None, address);
+                    self.stats.record(Resolution::Unresolved);
+                    return line;
+                }
+            }
+        } else {
+            let in_func_name = values.get("func").map(String::as_str);
+            let (module, offset) = (&values["module"], &values["offset"]);
+            let address = match parse_hex(offset) {
+                Ok(address) => address,
+                Err(_) => {
+                    self.warn_malformed(&line, "bad offset");
+                    self.push_unresolved(in_func_name, Some(module), offset);
+                    self.stats.record(Resolution::Unresolved);
+                    return line;
+                }
+            };
+            let raw_in_file_name = match self.frame_file_name(module) {
+                Some(raw_in_file_name) => raw_in_file_name,
+                None => {
+                    self.warn_malformed(&line, "bad JSON escape");
+                    self.push_unresolved(in_func_name, Some(module), offset);
+                    self.stats.record(Resolution::Unresolved);
+                    return line;
+                }
+            };
+            let (file_key, lookup_address) = self.frame_file_info(raw_in_file_name, address);
+            (file_key, address, lookup_address)
+        };
+
+        let file_info = self.file_info(&file_key);
+        let func_info = file_info.func_info(lookup_address);
+        let line_info = func_info.and_then(|func_info| func_info.line_info(lookup_address));
+        let resolution = match (func_info, line_info) {
+            (Some(_), Some(_)) => Resolution::Fixed,
+            (Some(_), None) => Resolution::NameOnly,
+            (None, _) => Resolution::Unresolved,
+        };
+        let signature_frame = self.signature.as_ref().map(|_| match func_info {
+            Some(func_info) => signature::normalize(&func_info.mangled_name),
+            None => match values.get("func") {
+                Some(in_func_name) if in_func_name != "???" => signature::normalize(in_func_name),
+                _ => signature::unresolved(
+                    values.get("module").map(String::as_str),
+                    &format!("{:x}", address),
+                ),
+            },
+        });
+        self.frame_values(&mut values, file_info, address, lookup_address);
+
+        let pattern = &self.frame_patterns[index];
+        let template = pattern
+            .templates
+            .get(resolution)
+            .or(pattern.template.as_ref())
+            .or_else(|| self.templates.get(resolution));
+        let out = match template {
+            Some(template) => template.render(&values),
+            None => format!(
+                "{}{} ({}){}",
+                values.get("prefix").map_or("", String::as_str),
+                values["function"],
+                values["location"],
+                values.get("suffix").map_or("", String::as_str),
+            ),
+        };
+        if let (Some(signature), Some(frame)) = (&mut self.signature, signature_frame) {
+            signature.push(frame);
+        }
+        self.stats.record(resolution);
+        out
+    }
+
+    /// Add the fields for a frame at `address` (the address given in the
+    /// input) to `values`, which has those from the input: `prefix`,
+    /// `frameno`, `func`, `module` and `suffix`, each of which is optional.
+    fn frame_values(
+        &self,
+        values: &mut FxHashMap<&str, String>,
+        file_info: &FileInfo,
+        address: u64,
+        lookup_address: u64,
+    ) {
+        // As in `fix`, new strings must be escaped in JSON mode, but strings
+        // from the input mustn't be.
+        let escape = |raw: String| match self.json_mode {
+            JsonMode::Yes => Fixer::json_escape(&raw),
+            JsonMode::No => raw,
+        };
+        let location = match values.get("module") {
+            Some(module) => format!("{} + 0x{:x}", module, address),
+            None => format!("0x{:x}", address),
+        };
+        values.insert("offset", format!("0x{:x}", address));
+        values.insert("location", location);
+
+        let func_info = match file_info.func_info(lookup_address) {
+            Some(func_info) => func_info,
+            None => {
+                let in_func_name = values.get("func").cloned();
+                let in_func_name = in_func_name.unwrap_or_else(|| "???".to_string());
+                values.insert("function", in_func_name.clone());
+                values.insert("short_function", in_func_name);
+                return;
+            }
+        };
+        values.insert(
+            "function",
+            escape(func_info.demangled_name(&self.name_options)),
+        );
+        values.insert(
+            "short_function",
+            escape(func_info.short_name(&self.name_options)),
+        );
+        values.insert(
+            "function_offset",
+            format!("0x{:x}", lookup_address - func_info.address),
+        );
+        values.insert("source", file_info.source.kind().to_string());
+
+        // As in the default output, the `--show-source` annotation goes just
+        // before the rest of the line.
+        if self.show_source {
+            let suffix = values.get("suffix").map_or("", String::as_str);
+            let suffix = format!("{}{}", self.source_annotation(&file_info.source), suffix);
+            values.insert("suffix", suffix);
+        }
+
+        if let Some(line_info) = func_info.line_info(lookup_address) {
+            let raw_file_name = file_info.interner.get(line_info.path);
+            let raw_basename = raw_file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+            let location = self.raw_location(raw_file_name, line_info.line, &file_info.source);
+            values.insert("location", escape(location));
+            values.insert("file", escape(raw_file_name.to_string()));
+            values.insert("basename", escape(raw_basename.to_string()));
+            values.insert("line", line_info.line.to_string());
+            if line_info.column != 0 {
+                values.insert("column", line_info.column.to_string());
+            }
+        }
+    }
+
+    /// Fix stack frames within `line` as necessary. Prints any errors to stderr.
+    #[inline]
+    fn fix(&mut self, line: String) -> String {
+        if self.perf_info.is_some() {
+            return self.fix_perf(line);
+        }
+
+        // Record the debug IDs from module lists, such as those produced by
+        // `minidump_stackwalk`, for verifying local files.
+        if let Some(module) = line.strip_prefix("Module|") {
+            let fields: Vec<_> = module.split('|').collect();
+            if let (Some(file_name), Some(debug_id)) = (fields.first(), fields.get(3)) {
+                if let Ok(debug_id) = DebugId::from_breakpad(debug_id) {
+                    self.module_ids
+                        .insert(file_name.to_string(), BuildId::Debug(debug_id));
+                }
+            }
+            return line;
+        }
+
+        // Apply the frame patterns from the config file, if any.
+        let configured = self
+            .frame_patterns
+            .iter()
+            .enumerate()
+            .find_map(|(index, pattern)| {
+                let captures = pattern.re.captures(&line)?;
+                let values: FxHashMap<_, _> = config::CAPTURES
+                    .iter()
+                    .filter_map(|&name| Some((name, captures.name(name)?.as_str().to_string())))
+                    .collect();
+                Some((index, values))
+            });
+        if let Some((index, values)) = configured {
+            return self.fix_configured(line, index, values);
+        }
+
+        // Apply the regexp.
+        let captures = if let Some(captures) = self.re.captures(&line) {
+            captures
+        } else {
+            return self.fix_tombstone(line);
+        };
+
+        let before = &captures[1];
+        let frameno = &captures[2];
+        let in_func_name = &captures[3];
+        let in_file_name = &captures[4];
+        let address = match u64::from_str_radix(&captures[5], 16) {
+            Ok(address) => address,
+            Err(_) => {
+                self.warn_malformed(&line, "address too large");
+                self.push_unresolved(Some(in_func_name), Some(in_file_name), &captures[5]);
+                self.stats.record(Resolution::Unresolved);
+                return line;
+            }
+        };
+        let after = &captures[6];
+
+        let raw_in_file_name = match self.frame_file_name(in_file_name) {
+            Some(raw_in_file_name) => raw_in_file_name,
+            None => {
+                self.warn_malformed(&line, "bad JSON escape");
+                self.push_unresolved(Some(in_func_name), Some(in_file_name), &captures[5]);
+                self.stats.record(Resolution::Unresolved);
+                return line;
+            }
+        };
+        let (file_key, lookup_address) = self.frame_file_info(raw_in_file_name, address);
+        let file_info = match &file_key {
+            Some(file_key) => &self.file_infos[file_key],
+            None => &self.jit_info.file_info,
+        };
+        if let Some(signature) = &mut self.signature {
+            signature.push(match file_info.func_info(lookup_address) {
+                Some(func_info) => signature::normalize(&func_info.mangled_name),
+                None if in_func_name != "???" => signature::normalize(in_func_name),
+                None => signature::unresolved(Some(in_file_name), &format!("{:x}", address)),
+            });
+        }
+
+        // Use the template for this resolution level, if there is one.
+        if !self.templates.is_empty() {
+            let func_info = file_info.func_info(lookup_address);
+            let resolution = match func_info.map(|f| f.line_info(lookup_address)) {
+                Some(Some(_)) => Resolution::Fixed,
+                Some(None) => Resolution::NameOnly,
+                None => Resolution::Unresolved,
+            };
+            if let Some(template) = self.templates.get(resolution) {
+                let mut values: FxHashMap<_, _> = [
+                    ("prefix", before),
+                    ("frameno", frameno),
+                    ("func", in_func_name),
+                    ("module", in_file_name),
+                    ("suffix", after),
+                ]
+                .iter()
+                .map(|&(name, value)| (name, value.to_string()))
+                .collect();
+                self.frame_values(&mut values, file_info, address, lookup_address);
+                let out = template.render(&values);
+                self.stats.record(resolution);
+                return out;
+            }
+        }
+
+        // In JSON mode, we need to escape any new strings we produce. However,
+        // strings from the input (i.e. `in_func_name` and `in_file_name`),
+        // will already be escaped, so if they are used in the output they
+        // shouldn't be re-escaped.
+        let (lb, rb) = self.brackets(&file_info.source);
+        if let Some(func_info) = file_info.func_info(lookup_address) {
+            let annotation = self.source_annotation(&file_info.source);
+            let raw_out_func_name = func_info.demangled_name(&self.name_options);
+            let out_func_name = if let JsonMode::Yes = self.json_mode {
+                Fixer::json_escape(&raw_out_func_name)
+            } else {
+                raw_out_func_name
+            };
+
+            if let Some(line_info) = func_info.line_info(lookup_address) {
+                // We have the function name, filename, and line number from
+                // the debug info.
+                self.stats.record(Resolution::Fixed);
+                let raw_location = self.raw_location(
+                    file_info.interner.get(line_info.path),
+                    line_info.line,
+                    &file_info.source,
+                );
+                let location = if let JsonMode::Yes = self.json_mode {
+                    Fixer::json_escape(&raw_location)
+                } else {
+                    raw_location
                 };
 
                 format!(
-                    "{}{} {}{}:{}{}{}",
-                    before, out_func_name, self.lb, out_file_name, line_info.line, self.rb, after
+                    "{}{} {}{}{}{}{}",
+                    before, out_func_name, lb, location, rb, annotation, after
                 )
             } else {
                 // We have the function name from the debug info, but no file
                 // name or line number. Use the file name and address from the
                 // original input.
+                self.stats.record(Resolution::NameOnly);
+                if let Source::Exports = file_info.source {
+                    // Export names are often far from the actual function, so
+                    // use the Windows debugger style, e.g.
+                    // `ntdll!RtlUserThreadStart + 0x21`, which shows the
+                    // offset from the export.
+                    let path = Path::new(file_key.as_deref().unwrap_or_default());
+                    let raw_module = path.file_stem().unwrap_or_default().to_string_lossy();
+                    let module = if let JsonMode::Yes = self.json_mode {
+                        Fixer::json_escape(&raw_module)
+                    } else {
+                        raw_module.into_owned()
+                    };
+                    return format!(
+                        "{}{}!{} + 0x{:x}{}{}",
+                        before,
+                        module,
+                        out_func_name,
+                        lookup_address - func_info.address,
+                        annotation,
+                        after
+                    );
+                }
                 format!(
-                    "{}{} {}{} + 0x{:x}{}{}",
-                    before, out_func_name, self.lb, in_file_name, address, self.rb, after
+                    "{}{} {}{} + 0x{:x}{}{}{}",
+                    before, out_func_name, lb, in_file_name, address, rb, annotation, after
                 )
             }
         } else {
@@ -887,9 +2707,10 @@ impl Fixer {
             // name, and address from the original input. The end result is the
             // same as the original line, but with slightly different
             // formatting.
+            self.stats.record(Resolution::Unresolved);
             format!(
                 "{}{} {}{} + 0x{:x}{}{}",
-                before, in_func_name, self.lb, in_file_name, address, self.rb, after
+                before, in_func_name, lb, in_file_name, address, rb, after
             )
         }
     }
@@ -903,28 +2724,397 @@ Post-process the stack frames produced by MozFormatCodeAddress().
 
 options:
   -h, --help              Show this message and exit
+      --config FILE       Read extra frame patterns from FILE, which is TOML.
+                          See the README for details
   -j, --json              Treat input and output as JSON fragments
+  -q, --quiet             Don't print warnings, or errors about files that
+                          can't be read
+  -v, --verbose           Also print the time taken to load each file, its
+                          number of functions, and where they came from
+      --template-fixed TEMPLATE
+      --template-name-only TEMPLATE
+      --template-unresolved TEMPLATE
+                          Print frames whose function and source location
+                          were found, whose function only was found, or which
+                          were unresolved, using TEMPLATE, in which `{field}`
+                          is replaced by the field's value. The fields are
+                          `prefix`, `frameno`, `function`, `short_function`,
+                          `location`, `file`, `basename`, `line`, `column`,
+                          `module`, `offset`, `function_offset`, `source` and
+                          `suffix`
+      --demangle STYLE    Print function names in STYLE, which is `full` (the
+                          default), `name-only` (without parameters or return
+                          type), `collapsed` (also without template
+                          arguments, e.g. `Foo<...>::bar`), or `mangled` (the
+                          full name followed by the mangled name)
+      --strip-rust-hash   Remove the `::h<hash>` suffix from Rust function
+                          names
+      --signature         Print a crash signature after each stack, e.g.
+                          `Signature: [@ moz_xmalloc | Foo<...>::Bar]`,
+                          skipping frames such as `abort` and keeping frames
+                          such as `moz_xmalloc` as a prefix. The config file
+                          can change these lists. Only for plain text input
+      --show-source       Add the source of each fixed frame's debug info
+                          after it, e.g. `{native}` or `{breakpad: PATH}`
+      --summary           At exit, print the number of frames seen, fixed,
+                          found with only a function name, and unresolved,
+                          and the modules that failed to load
+      --fail-on CONDITION Exit with a nonzero status if CONDITION holds (can
+                          be repeated). CONDITION is `load-error` (exit
+                          status 2) if a module failed to load, `mismatch`
+                          (3) if a file was ignored because of its build ID,
+                          `unresolved` (4) if a frame was unresolved, or
+                          `resolved-below=PERCENT` (5) if fewer than PERCENT
+                          percent of frames had their function found. If
+                          several hold, the lowest status is used
+      --strict            Same as `--fail-on load-error --fail-on mismatch
+                          --fail-on unresolved`
   -b, --breakpad DIR      Use breakpad symbols in directory DIR
   -l, --local DIR         Remap binary with same file name in DIR if the file
-                          is not found
+                          is not found (can be repeated). If the input gives
+                          the binary's build ID, only a file with that build
+                          ID is used
+      --local-recursive   Also look in subdirectories of the `--local`
+                          directories
+  -p, --pdb-dir DIR       Look for PDB files in DIR (can be repeated)
+  -s, --symbol-path PATH  Look for PDB files in the local directories and
+                          symbol stores in PATH, which has the same format as
+                          `_NT_SYMBOL_PATH` (used if this option is absent)
+      --srcsrv            Replace source paths from PDB files with their
+                          version control locations from the srcsrv stream
+      --source-url TEMPLATE
+                          Print a URL instead of the source location when the
+                          file name records its version control location.
+                          TEMPLATE is `hg`, `searchfox`, `github`, or a custom
+                          template containing `{repo}`, `{host}`,
+                          `{repo_path}`, `{repo_name}`, `{rev}`, `{path}` and
+                          `{line}`
+      --osc8              Print the source location as a terminal hyperlink
+                          to the source URL (`hg` if `--source-url` is absent)
+      --path-map FROM=TO  Replace FROM at the start of source paths with TO,
+                          which may be empty (can be repeated; the longest
+                          matching FROM is used). Windows paths are matched
+                          case-insensitively, with `\` and `/` equivalent.
+                          `.` and `..` path components are always removed
+      --vcs-scheme SCHEME Recognize file names of the form
+                          `SCHEME:REPO:PATH:REV` in addition to the built-in
+                          `hg`, `git` and `s3` schemes (can be repeated).
+                          SCHEME may be suffixed with `:none` if REV is always
+                          empty, otherwise REV must be a hex id. The config
+                          file's `[vcs]` table can also list schemes
+      --jit-map FILE      Resolve frames in JIT code, which have absolute
+                          addresses, using FILE, which is a `perf-<pid>.map`
+                          or jitdump file (can be repeated)
+      --perf              Treat input as the output of `perf script`, which
+                          has absolute addresses. They are converted using
+                          the mmap events (from `--show-mmap-events`) or the
+                          `--maps` file
+      --maps FILE         Use FILE, in the format of `/proc/<pid>/maps`, to
+                          convert addresses in `--perf` mode
+      --profile FILE      Instead of reading stdin, symbolicate FILE, which is
+                          a profile saved by the Firefox Profiler, and write
+                          the result to stdout. Libraries are found as for
+                          `--serve`
+      --dmd FILE          Instead of reading stdin, fix the frames in FILE,
+                          which is a DMD report (possibly gzipped), and write
+                          the result to stdout
+      --minidump-json FILE
+                          Instead of reading stdin, fill in the frames
+                          without symbols in FILE, which is the output of
+                          `minidump-stackwalk --json`, using the binaries in
+                          the `--local` directories, and write the result to
+                          stdout
+      --serve PORT        Instead of reading stdin, serve the Firefox
+                          Profiler's `/symbolicate/v5` API on
+                          `http://127.0.0.1:PORT`. Modules are found in the
+                          `--breakpad` directory, or by debug ID in the
+                          `--local` directories
+      --allow-origin ORIGIN
+                          Allow web pages from ORIGIN, e.g.
+                          `http://localhost:4242`, to use the `--serve` API
+                          (can be repeated). Only the Firefox Profiler's
+                          origin, `https://profiler.firefox.com`, is allowed
+                          if this option is absent
+      --build-id-dir DIR  Look for libraries named by build ID in DIR, which
+                          is laid out like `/usr/lib/debug/.build-id`, i.e.
+                          `DIR/ab/cdef....debug` (can be repeated)
 "##;
 
-fn main_inner() -> io::Result<()> {
+/// While a line with invalid UTF-8 is fixed, each invalid byte is represented
+/// by the character `INVALID_BYTE_BASE + byte`, a private use character, so
+/// that it passes through `Fixer::fix` unchanged.
+const INVALID_BYTE_BASE: u32 = 0x10ff00;
+
+fn is_invalid_byte_char(c: char) -> bool {
+    c as u32 >= INVALID_BYTE_BASE
+}
+
+/// Decode a line for fixing, representing invalid bytes as described above.
+/// Returns `None` if this can't be done reversibly, because the line already
+/// contains those characters.
+fn decode_line(bytes: &[u8]) -> Option<Cow<'_, str>> {
+    if let Ok(line) = str::from_utf8(bytes) {
+        return Some(Cow::Borrowed(line));
+    }
+    let mut line = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        if chunk.valid().chars().any(is_invalid_byte_char) {
+            return None;
+        }
+        line.push_str(chunk.valid());
+        line.extend(
+            chunk
+                .invalid()
+                .iter()
+                .filter_map(|&b| char::from_u32(INVALID_BYTE_BASE + u32::from(b))),
+        );
+    }
+    Some(Cow::Owned(line))
+}
+
+/// Encode a fixed line, converting the characters that represent invalid
+/// bytes back to those bytes, if `decode_line` used any.
+fn encode_line(line: &str, has_invalid_bytes: bool, out: &mut Vec<u8>) {
+    if !has_invalid_bytes {
+        out.extend_from_slice(line.as_bytes());
+        return;
+    }
+    for c in line.chars() {
+        if is_invalid_byte_char(c) {
+            out.push((c as u32 - INVALID_BYTE_BASE) as u8);
+        } else {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+}
+
+/// Fix the lines from `reader`, writing them to `writer`. The input is
+/// processed as bytes, so lines without stack frames are copied exactly,
+/// even if they aren't valid UTF-8, and line endings are preserved.
+fn fix_stream(
+    fixer: &mut Fixer,
+    mut reader: impl BufRead,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    let mut out = vec![];
+    let mut ended = true;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return write_signature(fixer, &mut writer, ended);
+        }
+        let ending_len = if buf.ends_with(b"\r\n") {
+            2
+        } else if buf.ends_with(b"\n") {
+            1
+        } else {
+            0
+        };
+        let (line, ending) = buf.split_at(buf.len() - ending_len);
+        let decoded = match decode_line(line) {
+            Some(decoded) => decoded,
+            None => {
+                write_signature(fixer, &mut writer, true)?;
+                writer.write_all(&buf)?;
+                ended = !ending.is_empty();
+                continue;
+            }
+        };
+        let has_invalid_bytes = matches!(decoded, Cow::Owned(_));
+        let frames = fixer.stats.frames;
+        let fixed = fixer.fix(decoded.into_owned());
+
+        // A line that isn't a frame ends the stack, if there is one.
+        if fixer.stats.frames == frames {
+            write_signature(fixer, &mut writer, true)?;
+        }
+
+        // `fix` can turn one line into several, e.g. for inlined functions in
+        // `perf` mode. They get the same line ending as the input line.
+        out.clear();
+        for (i, fixed_line) in fixed.split('\n').enumerate() {
+            if i > 0 {
+                out.extend_from_slice(if ending.is_empty() { b"\n" } else { ending });
+            }
+            encode_line(fixed_line, has_invalid_bytes, &mut out);
+        }
+        out.extend_from_slice(ending);
+        writer.write_all(&out)?;
+        ended = !ending.is_empty();
+    }
+}
+
+/// Print the signature of the stack just fixed, if `--signature` was given
+/// and there is one. `ended` says if the last line written had a line ending.
+fn write_signature(fixer: &mut Fixer, writer: &mut impl Write, ended: bool) -> io::Result<()> {
+    if let Some(signature) = fixer.signature.as_mut().and_then(SignatureInfo::take) {
+        if !ended {
+            writeln!(writer)?;
+        }
+        writeln!(writer, "Signature: {}", signature)?;
+    }
+    Ok(())
+}
+
+/// How a JSON file was written, so that the fixed JSON can be written the
+/// same way.
+struct JsonFormat {
+    gzipped: bool,
+
+    /// Was it pretty-printed, rather than minified?
+    pretty: bool,
+}
+
+/// Read a JSON file, which may be gzipped.
+fn read_json_file(file: &str) -> Result<(Value, JsonFormat)> {
+    let mut data = fs::read(file).context("read")?;
+    let gzipped = data.starts_with(&[0x1f, 0x8b]);
+    if gzipped {
+        let mut json = vec![];
+        GzDecoder::new(&data[..])
+            .read_to_end(&mut json)
+            .context("decompress")?;
+        data = json;
+    }
+    let value = serde_json::from_slice(&data).context("parse")?;
+
+    // Minified JSON has no line breaks, except maybe at the end.
+    let pretty = data.trim_ascii_end().contains(&b'\n');
+    Ok((value, JsonFormat { gzipped, pretty }))
+}
+
+/// Write `value` in `format`, followed by a newline, and gzipped if the
+/// input was. Object keys are written in the order they were read.
+fn write_json(writer: impl Write, value: &Value, format: &JsonFormat) -> io::Result<()> {
+    fn write_text(mut writer: impl Write, value: &Value, pretty: bool) -> io::Result<()> {
+        if pretty {
+            serde_json::to_writer_pretty(&mut writer, value)?;
+        } else {
+            serde_json::to_writer(&mut writer, value)?;
+        }
+        writeln!(writer)
+    }
+
+    if format.gzipped {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        write_text(&mut encoder, value, format.pretty)?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        write_text(writer, value, format.pretty)
+    }
+}
+
+/// Read a JSON file, which may be gzipped, apply `f` to it, and write the
+/// result to stdout. Errors in the file are fatal.
+fn fix_json_file(file: &str, f: impl FnOnce(&mut Value) -> Result<()>) -> io::Result<()> {
+    let (mut value, format) = read_json_file(file).map_err(|err| file_error(err, file))?;
+    f(&mut value).map_err(|err| file_error(err, file))?;
+    write_json(io::BufWriter::new(io::stdout().lock()), &value, &format)
+}
+
+/// Convert a fatal error about `file` into an `io::Error` whose message
+/// includes its causes, one per line.
+fn file_error(err: anyhow::Error, file: &str) -> io::Error {
+    let causes: String = err
+        .chain()
+        .skip(1)
+        .map(|cause| format!("\nfix-stacks: {}", cause))
+        .collect();
+    io::Error::other(format!("failed to {} `{}`{}", err, file, causes))
+}
+
+/// Returns the exit status, which is nonzero if a `--fail-on` condition
+/// holds.
+fn main_inner() -> io::Result<i32> {
     // Process command line arguments. The arguments are simple enough for now
     // that using an external crate doesn't seem worthwhile.
     let mut json_mode = JsonMode::No;
     let mut bp_info = None;
-    let mut local_info = None;
-
-    let err = |msg| Err(io::Error::new(io::ErrorKind::Other, msg));
+    let mut local_dirs = vec![];
+    let mut local_recursive = false;
+    let mut pdb_info = PdbInfo::default();
+    let mut symbol_path = env::var("_NT_SYMBOL_PATH").ok();
+    let mut source_url = None;
+    let mut osc8 = false;
+    let mut vcs_schemes = VcsScheme::builtin();
+    let mut path_map = PathMap::default();
+    let mut build_id_dirs = vec![];
+    let mut jit_files = vec![];
+    let mut perf = false;
+    let mut maps_file = None;
+    let mut serve_port = None;
+    let mut allowed_origins = vec![];
+    let mut profile_file = None;
+    let mut dmd_file = None;
+    let mut minidump_json_file = None;
+    let mut summary = false;
+    let mut show_source = false;
+    let mut signature = false;
+    let mut name_options = NameOptions::default();
+    let mut config_file = None;
+    let mut templates = vec![];
+    let mut fail_on = vec![];
+    let mut level = Level::default();
+
+    let err = |msg| Err(io::Error::other(msg));
 
     let mut args = env::args().skip(1);
     while let Some(arg) = args.next() {
         if arg == "-h" || arg == "--help" {
             println!("{}", USAGE_MSG);
-            return Ok(());
+            return Ok(0);
         } else if arg == "-j" || arg == "--json" {
             json_mode = JsonMode::Yes;
+        } else if arg == "-q" || arg == "--quiet" {
+            level = Level::Quiet;
+        } else if arg == "-v" || arg == "--verbose" {
+            level = Level::Verbose;
+        } else if arg == "--config" {
+            match args.next() {
+                Some(arg2) => config_file = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if let Some(level) = arg.strip_prefix("--template-") {
+            match args.next() {
+                Some(arg2) => templates.push((level.to_string(), arg2)),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--demangle" {
+            match args.next() {
+                Some(arg2) => match demangle::Style::parse(&arg2) {
+                    Ok(style) => name_options.style = style,
+                    Err(msg) => return err(msg),
+                },
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--strip-rust-hash" {
+            name_options.strip_rust_hash = true;
+        } else if arg == "--signature" {
+            signature = true;
+        } else if arg == "--show-source" {
+            show_source = true;
+        } else if arg == "--summary" {
+            summary = true;
+        } else if arg == "--strict" {
+            fail_on.extend(FailOn::STRICT);
+        } else if arg == "--fail-on" {
+            match args.next() {
+                Some(arg2) => match FailOn::parse(&arg2) {
+                    Ok(condition) => fail_on.push(condition),
+                    Err(msg) => return err(msg),
+                },
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
         } else if arg == "-b" || arg == "--breakpad" {
             match args.next() {
                 Some(arg2) => {
@@ -937,16 +3127,121 @@ fn main_inner() -> io::Result<()> {
                 }
             }
         } else if arg == "-l" || arg == "--local" {
+            match args.next() {
+                Some(arg2) => local_dirs.push(PathBuf::from(arg2)),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--local-recursive" {
+            local_recursive = true;
+        } else if arg == "-p" || arg == "--pdb-dir" {
+            match args.next() {
+                Some(arg2) => pdb_info.dirs.push(PathBuf::from(arg2)),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--srcsrv" {
+            pdb_info.srcsrv = true;
+        } else if arg == "--source-url" {
+            match args.next() {
+                Some(arg2) => source_url = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--osc8" {
+            osc8 = true;
+        } else if arg == "--jit-map" {
+            match args.next() {
+                Some(arg2) => jit_files.push(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--perf" {
+            perf = true;
+        } else if arg == "--maps" {
+            match args.next() {
+                Some(arg2) => maps_file = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--profile" {
+            match args.next() {
+                Some(arg2) => profile_file = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--dmd" {
+            match args.next() {
+                Some(arg2) => dmd_file = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--minidump-json" {
+            match args.next() {
+                Some(arg2) => minidump_json_file = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--serve" {
+            match args.next() {
+                Some(arg2) => match arg2.parse::<u16>() {
+                    Ok(port) => serve_port = Some(port),
+                    Err(_) => return err(format!("bad port `{}`.", arg2)),
+                },
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--allow-origin" {
+            match args.next() {
+                Some(arg2) => allowed_origins.push(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--build-id-dir" {
+            match args.next() {
+                Some(arg2) => build_id_dirs.push(PathBuf::from(arg2)),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "--path-map" {
             match args.next() {
                 Some(arg2) => {
-                    local_info = Some(LocalFileInfo {
-                        local_dir: arg2.to_string(),
-                    });
+                    if let Err(msg) = path_map.add(&arg2) {
+                        return err(msg);
+                    }
                 }
                 _ => {
                     return err(format!("missing argument to option `{}`.", arg));
                 }
             }
+        } else if arg == "--vcs-scheme" {
+            match args.next() {
+                Some(arg2) => match VcsScheme::from_spec(&arg2) {
+                    Ok(scheme) => vcs_schemes.push(scheme),
+                    Err(msg) => return err(msg),
+                },
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
+        } else if arg == "-s" || arg == "--symbol-path" {
+            match args.next() {
+                Some(arg2) => symbol_path = Some(arg2),
+                _ => {
+                    return err(format!("missing argument to option `{}`.", arg));
+                }
+            }
         } else {
             let msg = format!(
                 "bad argument `{}`. Run `fix-stacks -h` for more information.",
@@ -956,22 +3251,147 @@ fn main_inner() -> io::Result<()> {
         }
     }
 
-    let reader = io::BufReader::new(io::stdin());
+    if let Some(symbol_path) = symbol_path {
+        pdb_info.add_symbol_path(&symbol_path);
+    }
+
+    let local_info = if local_dirs.is_empty() {
+        None
+    } else {
+        Some(LocalFileInfo {
+            local_dirs,
+            recursive: local_recursive,
+        })
+    };
+
+    // The signature lines would break the JSON fragments.
+    if signature && matches!(json_mode, JsonMode::Yes) {
+        return err("`--signature` can't be used with `--json`.".to_string());
+    }
+
+    // `perf script` samples aren't crash stacks, and their frames aren't
+    // added to signatures.
+    if signature && (perf || maps_file.is_some()) {
+        return err("`--signature` can't be used with `--perf` or `--maps`.".to_string());
+    }
+
+    // The other modes rewrite structured data, which has no place for
+    // signature lines.
+    if signature
+        && (serve_port.is_some()
+            || profile_file.is_some()
+            || dmd_file.is_some()
+            || minidump_json_file.is_some())
+    {
+        return err(
+            "`--signature` can't be used with `--serve`, `--profile`, `--dmd` or \
+             `--minidump-json`."
+                .to_string(),
+        );
+    }
+
+    // The server never exits, so there would be no summary or exit status.
+    if serve_port.is_some() && (summary || !fail_on.is_empty()) {
+        return err("`--summary` and `--fail-on` can't be used with `--serve`.".to_string());
+    }
+
+    let perf_info = if perf || maps_file.is_some() {
+        let maps = match maps_file {
+            Some(maps_file) => perf::read_maps_file(&maps_file)
+                .map_err(|e| io::Error::other(format!("failed to {} `{}`", e, maps_file)))?,
+            None => vec![],
+        };
+        Some(PerfInfo::new(maps))
+    } else {
+        None
+    };
+
+    let mut config = match config_file {
+        Some(config_file) => {
+            config::read_config(&config_file).map_err(|e| file_error(e, &config_file))?
+        }
+        None => Config::default(),
+    };
+
+    for (level, template) in templates {
+        if let Err(msg) = config.templates.set(&level.replace('-', "_"), &template) {
+            return err(format!("bad `--template-{}` option: {}.", level, msg));
+        }
+    }
 
     let mut fixer = Fixer::new(json_mode, bp_info, local_info);
-    for line in reader.lines() {
-        writeln!(io::stdout(), "{}", fixer.fix(line.unwrap()))?;
+    fixer.templates = config.templates;
+    fixer.frame_patterns = config.frame_patterns;
+    fixer.pdb_info = pdb_info;
+    vcs_schemes.extend(config.vcs_schemes);
+    fixer.vcs_schemes = vcs_schemes;
+    fixer.path_map = path_map;
+    fixer.build_id_dirs = build_id_dirs;
+    fixer.keep_inlinees = perf_info.is_some();
+    fixer.perf_info = perf_info;
+    fixer.level = level;
+    fixer.show_source = show_source;
+    fixer.name_options = name_options;
+    if signature {
+        fixer.signature = Some(config.signature);
+    }
+    fixer.jit_info = JitInfo {
+        file_info: Fixer::build_file_info_jit(&jit_files, level),
+        jit_files,
+    };
+    if source_url.is_some() || osc8 {
+        let template = source_url.as_deref().unwrap_or("hg");
+        fixer.source_link_info = Some(SourceLinkInfo::new(template, osc8));
     }
 
-    Ok(())
+    let result = if let Some(profile_file) = profile_file {
+        fix_json_file(&profile_file, |profile| fixer.symbolicate_profile(profile))
+    } else if let Some(dmd_file) = dmd_file {
+        fix_json_file(&dmd_file, |report| fixer.fix_dmd(report))
+    } else if let Some(minidump_json_file) = minidump_json_file {
+        fix_json_file(&minidump_json_file, |report| {
+            fixer.fix_minidump_json(report)
+        })
+    } else if let Some(port) = serve_port {
+        // Only local connections are accepted, because the server can read
+        // any binary it's asked about.
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("fix-stacks: serving on http://127.0.0.1:{}", port);
+        fixer.keep_inlinees = true;
+        if allowed_origins.is_empty() {
+            allowed_origins.push(server::DEFAULT_ORIGIN.to_string());
+        }
+        server::serve(listener, &allowed_origins, level, |request| {
+            fixer.handle_request(request)
+        });
+        Ok(())
+    } else {
+        fix_stream(&mut fixer, io::stdin().lock(), io::stdout())
+    };
+
+    // The summary and the `--fail-on` conditions cover the frames fixed
+    // before any error.
+    if summary {
+        fixer.stats.print_summary();
+    }
+    let status = fixer.stats.exit_status(&fail_on, fixer.level);
+    match result {
+        // Output piped through e.g. `head -10` is cut short, but that's fine.
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+        _ => Ok(status),
+    }
 }
 
 fn main() {
     // Ignore broken pipes, e.g. when piping output through `head -10`.
-    if let Err(err) = main_inner() {
-        if err.kind() != io::ErrorKind::BrokenPipe {
-            eprintln!("fix-stacks: {}", err);
-            std::process::exit(1);
+    match main_inner() {
+        Ok(0) => {}
+        Ok(status) => std::process::exit(status),
+        Err(err) => {
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("fix-stacks: {}", err);
+                std::process::exit(1);
+            }
         }
     }
 }
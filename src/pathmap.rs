@@ -0,0 +1,143 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Rewriting of source paths from debug info, so that frames built on
+//! different machines and platforms print the same paths. `.` and `..`
+//! components are removed, and then the longest matching `--path-map` prefix
+//! is replaced.
+
+/// Is this a Windows path, e.g. `c:\Users\njn\foo.c` or
+/// `\\server\share\foo.c`? Such paths are matched case-insensitively, and `\`
+/// and `/` are treated as the same separator.
+fn is_windows_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    path.contains('\\') || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// Remove `.` components, and `..` components along with the component
+/// before them, if possible. Empty components, e.g. the first one in an
+/// absolute path, are kept.
+pub fn normalize(path: &str) -> String {
+    let windows = is_windows_path(path);
+    let is_separator = |c: char| c == '/' || (windows && c == '\\');
+    let separator = path.chars().find(|&c| is_separator(c)).unwrap_or('/');
+
+    let mut components: Vec<&str> = vec![];
+    for component in path.split(is_separator) {
+        match component {
+            "." => {}
+            ".." => match components.last() {
+                Some(&last) if !last.is_empty() && last != ".." && !last.ends_with(':') => {
+                    components.pop();
+                }
+                _ => components.push(component),
+            },
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return ".".to_string();
+    }
+    components.join(&separator.to_string())
+}
+
+/// A `--path-map FROM=TO` rule.
+struct Rule {
+    from: String,
+    to: String,
+}
+
+/// The `--path-map` rules.
+#[derive(Default)]
+pub struct PathMap {
+    rules: Vec<Rule>,
+}
+
+impl PathMap {
+    /// Add a rule given as `FROM=TO`. `TO` may be empty, to make paths
+    /// relative.
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        match spec.split_once('=') {
+            Some((from, to)) if !from.is_empty() => {
+                self.rules.push(Rule {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                });
+                Ok(())
+            }
+            _ => Err(format!("bad `--path-map` rule `{}`.", spec)),
+        }
+    }
+
+    /// If `path` starts with `from`, ending at a component boundary, get the
+    /// rest of it, which is empty or starts with a separator unless `from`
+    /// ends with one.
+    fn strip_prefix<'a>(path: &'a str, from: &str, windows: bool) -> Option<&'a str> {
+        let from_len = from.len();
+        if path.len() < from_len || !path.is_char_boundary(from_len) {
+            return None;
+        }
+        let (prefix, rest) = path.split_at(from_len);
+        let matches = if windows {
+            prefix.chars().zip(from.chars()).all(|(a, b)| match (a, b) {
+                ('/' | '\\', '/' | '\\') => true,
+                _ => a.eq_ignore_ascii_case(&b),
+            })
+        } else {
+            prefix == from
+        };
+        let at_boundary = rest.is_empty()
+            || from.ends_with(['/', '\\'])
+            || rest.starts_with('/')
+            || (windows && rest.starts_with('\\'));
+        if matches && at_boundary {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Normalize `path` and apply the rule with the longest matching prefix,
+    /// if any. Returns `None` if the path is unchanged.
+    pub fn map(&self, path: &str) -> Option<String> {
+        // URLs, e.g. from srcsrv streams, are left alone.
+        if path.contains("://") {
+            return None;
+        }
+        let normalized = normalize(path);
+        let windows = is_windows_path(&normalized);
+        let best = self
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                PathMap::strip_prefix(&normalized, &rule.from, windows).map(|rest| (rule, rest))
+            })
+            .max_by_key(|(rule, _)| rule.from.len());
+        let new_path = match best {
+            Some((rule, rest)) => {
+                // Use `/` in the rest of a Windows path, so it matches the
+                // same path from other platforms, unless `TO` uses `\`.
+                let rest = if windows && !rule.to.contains('\\') {
+                    rest.replace('\\', "/")
+                } else {
+                    rest.to_string()
+                };
+                if rule.to.is_empty() {
+                    rest.trim_start_matches(['/', '\\']).to_string()
+                } else if rule.to.ends_with(['/', '\\']) {
+                    format!("{}{}", rule.to, rest.trim_start_matches(['/', '\\']))
+                } else {
+                    format!("{}{}", rule.to, rest)
+                }
+            }
+            None => normalized,
+        };
+        if new_path == path {
+            None
+        } else {
+            Some(new_path)
+        }
+    }
+}
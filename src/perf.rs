@@ -0,0 +1,192 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of `perf script` output, whose samples look like this:
+//!
+//! ```text
+//! firefox  1234 12345.678901:     250000 cpu-clock:
+//!             7f12a3b4c5d6 [unknown] (/objdir/dist/bin/libxul.so)
+//!             55d0c1a2b3c4 main+0x14 (/objdir/dist/bin/firefox)
+//!
+//! ```
+//!
+//! The frame addresses are absolute, so they must be converted to offsets
+//! within modules using the memory mappings of the sampled process. These
+//! come from `PERF_RECORD_MMAP` and `PERF_RECORD_MMAP2` events (printed by
+//! `perf script --show-mmap-events`) or from a file in the format of
+//! `/proc/<pid>/maps`.
+
+use anyhow::{Context, Result};
+use fxhash::FxHashMap;
+use regex::Regex;
+use std::fs;
+
+/// A memory mapping of part of a file.
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+
+    /// The offset within the file of the start of the mapping.
+    pub file_offset: u64,
+
+    pub path: String,
+}
+
+/// A line of `perf script` output that this module knows about.
+pub enum PerfLine<'a> {
+    /// The first line of a sample.
+    Sample { pid: u32 },
+
+    /// A mapping of a file into a process.
+    Mmap { pid: u32, mapping: Mapping },
+
+    /// A stack frame.
+    Frame {
+        /// The indentation.
+        before: &'a str,
+        address: u64,
+        /// The module, e.g. a path, `[unknown]` or `[kernel.kallsyms]`.
+        dso: &'a str,
+    },
+}
+
+pub struct PerfParser {
+    sample_re: Regex,
+    mmap_re: Regex,
+    frame_re: Regex,
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+impl PerfParser {
+    pub fn new() -> PerfParser {
+        PerfParser {
+            // The command name can contain spaces, e.g. `Web Content`. The
+            // pid can be followed by a tid, and then by a CPU number.
+            sample_re: Regex::new(r"^\S.*?\s+(\d+)(?:/\d+)?\s+(?:\[\d+\]\s+)?\d+\.\d+:").unwrap(),
+            mmap_re: Regex::new(
+                r"PERF_RECORD_MMAP2? (\d+)/\d+: \[0x([0-9a-f]+)\(0x([0-9a-f]+)\) @ (0x[0-9a-f]+|0)[ \]].*\]: \S+ (.+)$",
+            )
+            .unwrap(),
+            frame_re: Regex::new(r"^(\s+)([0-9a-f]+) .* \((.+)\)$").unwrap(),
+        }
+    }
+
+    pub fn parse<'a>(&self, line: &'a str) -> Option<PerfLine<'a>> {
+        // Check for mmap events first, because they also look like the first
+        // line of a sample.
+        if let Some(captures) = self.mmap_re.captures(line) {
+            let start = parse_hex(&captures[2])?;
+            return Some(PerfLine::Mmap {
+                pid: captures[1].parse().ok()?,
+                mapping: Mapping {
+                    start,
+                    end: start.checked_add(parse_hex(&captures[3])?)?,
+                    file_offset: parse_hex(&captures[4])?,
+                    path: captures[5].to_string(),
+                },
+            });
+        }
+        if let Some(captures) = self.frame_re.captures(line) {
+            return Some(PerfLine::Frame {
+                before: captures.get(1).unwrap().as_str(),
+                address: u64::from_str_radix(&captures[2], 16).ok()?,
+                dso: captures.get(3).unwrap().as_str(),
+            });
+        }
+        if let Some(captures) = self.sample_re.captures(line) {
+            return Some(PerfLine::Sample {
+                pid: captures[1].parse().ok()?,
+            });
+        }
+        None
+    }
+}
+
+/// Read a file in the format of `/proc/<pid>/maps`. Only mappings of files
+/// are returned, not anonymous mappings or ones like `[stack]`.
+pub fn read_maps_file(maps_file: &str) -> Result<Vec<Mapping>> {
+    let text = fs::read_to_string(maps_file).context("read")?;
+    let mut mappings = vec![];
+    for line in text.lines() {
+        // E.g. `7f12a0000000-7f12a5000000 r-xp 00001000 fd:01 123  /lib/libfoo.so`.
+        // The path is the rest of the line after the first five fields, and
+        // may contain spaces.
+        let mut rest = line;
+        let mut fields = vec![];
+        for _ in 0..5 {
+            let trimmed = rest.trim_start();
+            let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+            fields.push(&trimmed[..end]);
+            rest = &trimmed[end..];
+        }
+        let path = rest.trim();
+        if let [range, _, offset, _, _] = fields[..] {
+            if range.is_empty() || path.is_empty() {
+                continue;
+            }
+            if path.starts_with('[') {
+                continue;
+            }
+            let (start, end) = range.split_once('-').context("parse")?;
+            let parse = |s| u64::from_str_radix(s, 16).context("parse");
+            mappings.push(Mapping {
+                start: parse(start)?,
+                end: parse(end)?,
+                file_offset: parse(offset)?,
+                path: path.to_string(),
+            });
+        }
+    }
+    Ok(mappings)
+}
+
+/// The state needed to process `perf script` output.
+pub struct PerfInfo {
+    pub parser: PerfParser,
+
+    /// The pid of the current sample.
+    pid: Option<u32>,
+
+    /// The mappings from mmap events, per pid.
+    mmaps: FxHashMap<u32, Vec<Mapping>>,
+
+    /// The mappings from the `--maps` file, which are used for all pids.
+    maps: Vec<Mapping>,
+}
+
+impl PerfInfo {
+    pub fn new(maps: Vec<Mapping>) -> PerfInfo {
+        PerfInfo {
+            parser: PerfParser::new(),
+            pid: None,
+            mmaps: FxHashMap::default(),
+            maps,
+        }
+    }
+
+    pub fn set_pid(&mut self, pid: u32) {
+        self.pid = Some(pid);
+    }
+
+    pub fn add_mmap(&mut self, pid: u32, mapping: Mapping) {
+        self.mmaps.entry(pid).or_default().push(mapping);
+    }
+
+    /// Find the mapping of `path` containing `address` in the current
+    /// sample's process. Later mappings take precedence, because they can
+    /// replace earlier ones.
+    pub fn mapping(&self, address: u64, path: &str) -> Option<&Mapping> {
+        let contains = |mapping: &&Mapping| {
+            mapping.start <= address && address < mapping.end && mapping.path == path
+        };
+        self.pid
+            .and_then(|pid| self.mmaps.get(&pid))
+            .and_then(|mmaps| mmaps.iter().rev().find(contains))
+            .or_else(|| self.maps.iter().rev().find(contains))
+    }
+}
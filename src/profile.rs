@@ -0,0 +1,238 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Symbolication of profiles in the Firefox Profiler's processed format, as
+//! saved by the profiler. The relevant parts look like this:
+//!
+//! ```text
+//! {
+//!   "libs": [{"debugName": "libxul.so", "breakpadId": "<debug ID>", ...}, ...],
+//!   "threads": [{
+//!     "resourceTable": {"lib": [<lib index>, ...], ...},
+//!     "funcTable": {"name": [<string index>, ...], "resource": [...],
+//!                   "fileName": [...], "lineNumber": [...], ..., "length": N},
+//!     "frameTable": {"address": [<address within the lib>, ...],
+//!                    "func": [<func index>, ...], "line": [...], ...},
+//!     "stringArray": ["0x1234", ...],
+//!   }, ...]
+//! }
+//! ```
+//!
+//! An unsymbolicated profile has one function per native frame address, named
+//! after the address. Each symbolicated frame is pointed at a function with
+//! the real name and file, which is shared by all frames in that function.
+//! Older profiles call `stringArray` `stringTable`, and newer ones share a
+//! single `stringArray` between threads, in `shared`.
+
+use anyhow::{bail, Context, Result};
+use fxhash::FxHashMap;
+use serde_json::{json, Value};
+
+/// A library in the profile.
+pub struct Lib {
+    pub debug_name: String,
+    pub breakpad_id: String,
+}
+
+/// The symbol for a frame address.
+pub struct Symbol {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Get the libraries in a profile.
+pub fn libs(profile: &Value) -> Result<Vec<Lib>> {
+    let libs = profile["libs"].as_array().context("read `libs` in")?;
+    libs.iter()
+        .map(
+            |lib| match (lib["debugName"].as_str(), lib["breakpadId"].as_str()) {
+                (Some(debug_name), Some(breakpad_id)) => Ok(Lib {
+                    debug_name: debug_name.to_string(),
+                    breakpad_id: breakpad_id.to_string(),
+                }),
+                _ => bail!("read lib `{}` in", lib),
+            },
+        )
+        .collect()
+}
+
+/// A string array with an index for adding strings without duplicates.
+struct Strings {
+    array: Vec<Value>,
+    index: FxHashMap<String, usize>,
+}
+
+impl Strings {
+    fn new(array: Vec<Value>) -> Strings {
+        let mut index = FxHashMap::default();
+        for (i, string) in array.iter().enumerate() {
+            if let Some(string) = string.as_str() {
+                index.entry(string.to_string()).or_insert(i);
+            }
+        }
+        Strings { array, index }
+    }
+
+    fn intern(&mut self, string: &str) -> usize {
+        if let Some(&i) = self.index.get(string) {
+            return i;
+        }
+        let i = self.array.len();
+        self.array.push(json!(string));
+        self.index.insert(string.to_string(), i);
+        i
+    }
+}
+
+/// Get a field of an object, e.g. a table of a thread.
+fn field<'a>(object: &'a mut Value, name: &str) -> Result<&'a mut Value> {
+    object
+        .get_mut(name)
+        .with_context(|| format!("read `{}` in", name))
+}
+
+/// Get a column of a table as a mutable array.
+fn column<'a>(table: &'a mut Value, name: &str) -> Result<&'a mut Vec<Value>> {
+    field(table, name)?
+        .as_array_mut()
+        .with_context(|| format!("read `{}` in", name))
+}
+
+/// Symbolicate every native frame in `profile`. `lookup` is called with the
+/// index of a library in `libs` and an address within it.
+pub fn symbolicate(
+    profile: &mut Value,
+    mut lookup: impl FnMut(usize, u64) -> Option<Symbol>,
+) -> Result<()> {
+    let mut shared_strings = match profile.pointer_mut("/shared/stringArray") {
+        Some(array) => {
+            let array = array.as_array_mut().context("read `stringArray` in")?;
+            Some(Strings::new(array.split_off(0)))
+        }
+        None => None,
+    };
+
+    let threads = column(profile, "threads")?;
+    for thread in threads.iter_mut() {
+        let key = ["stringArray", "stringTable"]
+            .iter()
+            .copied()
+            .find(|key| matches!(thread.get(key), Some(Value::Array(_))));
+        match (key, &mut shared_strings) {
+            (Some(key), _) => {
+                let mut strings = Strings::new(column(thread, key)?.split_off(0));
+                symbolicate_thread(thread, &mut strings, &mut lookup)?;
+                thread[key] = Value::Array(strings.array);
+            }
+            (None, Some(strings)) => symbolicate_thread(thread, strings, &mut lookup)?,
+            (None, None) => bail!("read `stringArray` in"),
+        }
+    }
+
+    if let Some(strings) = shared_strings {
+        profile["shared"]["stringArray"] = Value::Array(strings.array);
+    }
+    if let Some(meta) = profile.get_mut("meta").and_then(Value::as_object_mut) {
+        meta.insert("symbolicated".to_string(), json!(true));
+    }
+    Ok(())
+}
+
+fn symbolicate_thread(
+    thread: &mut Value,
+    strings: &mut Strings,
+    lookup: &mut impl FnMut(usize, u64) -> Option<Symbol>,
+) -> Result<()> {
+    let resource_libs: Vec<_> = column(field(thread, "resourceTable")?, "lib")?
+        .iter()
+        .map(Value::as_u64)
+        .collect();
+    let func_resources: Vec<_> = column(field(thread, "funcTable")?, "resource")?
+        .iter()
+        .map(Value::as_u64)
+        .collect();
+
+    // The new functions, by resource, name and file.
+    let mut new_funcs: FxHashMap<(u64, usize, Option<usize>), usize> = FxHashMap::default();
+
+    let frame_table = field(thread, "frameTable")?;
+    let addresses: Vec<_> = column(frame_table, "address")?
+        .iter()
+        .map(Value::as_u64)
+        .collect();
+    let frame_funcs: Vec<_> = column(frame_table, "func")?
+        .iter()
+        .map(Value::as_u64)
+        .collect();
+    for (i, (address, func)) in addresses.into_iter().zip(frame_funcs).enumerate() {
+        // Frames without an address, e.g. JS frames, have an address of -1.
+        let (address, func) = match (address, func) {
+            (Some(address), Some(func)) => (address, func as usize),
+            _ => continue,
+        };
+        let resource = match func_resources.get(func).copied().flatten() {
+            Some(resource) => resource,
+            None => continue,
+        };
+        let lib = match resource_libs.get(resource as usize).copied().flatten() {
+            Some(lib) => lib as usize,
+            None => continue,
+        };
+        let symbol = match lookup(lib, address) {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+
+        let name = strings.intern(&symbol.name);
+        let file = symbol.file.as_deref().map(|file| strings.intern(file));
+        let new_func = match new_funcs.get(&(resource, name, file)) {
+            Some(&new_func) => new_func,
+            None => {
+                let new_func = add_func(field(thread, "funcTable")?, func, name, file)?;
+                new_funcs.insert((resource, name, file), new_func);
+                new_func
+            }
+        };
+
+        let frame_table = field(thread, "frameTable")?;
+        column(frame_table, "func")?[i] = json!(new_func);
+        if let Ok(lines) = column(frame_table, "line") {
+            if let Some(line) = lines.get_mut(i) {
+                *line = json!(symbol.line);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add a function to `func_table`, which is a copy of the function at index
+/// `func` apart from its name and file. Returns its index.
+fn add_func(
+    func_table: &mut Value,
+    func: usize,
+    name: usize,
+    file: Option<usize>,
+) -> Result<usize> {
+    let length = field(func_table, "length")?
+        .as_u64()
+        .context("read `length` in")? as usize;
+    let columns = func_table.as_object_mut().context("read `funcTable` in")?;
+    for (key, column) in columns.iter_mut() {
+        let column = match column.as_array_mut() {
+            Some(column) if column.len() == length => column,
+            _ => continue,
+        };
+        let value = match key.as_str() {
+            "name" => json!(name),
+            "fileName" => json!(file),
+            "lineNumber" | "columnNumber" => Value::Null,
+            _ => column.get(func).cloned().unwrap_or(Value::Null),
+        };
+        column.push(value);
+    }
+    columns.insert("length".to_string(), json!(length + 1));
+    Ok(length)
+}
@@ -0,0 +1,250 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A minimal HTTP/1.1 server, just capable enough to serve the Firefox
+//! Profiler's symbolication API to a browser on the same machine. Requests are
+//! handled one at a time, and each connection is closed after its response.
+//! Connections time out, so that an idle client can't block the others, and
+//! request heads and bodies are size-limited.
+//!
+//! Only pages from the allowed origins, which are the profiler's by default,
+//! can make requests from a browser. Otherwise any web page could read the
+//! paths of local binaries and source files.
+
+use crate::diag::{self, Level};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Request bodies larger than this are rejected. Symbolication requests for
+/// large profiles are a few megabytes.
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// Request lines and headers longer than this are rejected, as are request
+/// heads (the request line and all the headers) larger than
+/// `MAX_HEAD_SIZE`. Otherwise a client could send an endless line.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How long to wait for a client to send its request or accept the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The origin allowed to make requests if `--allow-origin` isn't given.
+pub const DEFAULT_ORIGIN: &str = "https://profiler.firefox.com";
+
+/// An error for a request whose head is too large, which gets a 431 response
+/// rather than a 400 one.
+#[derive(Debug)]
+struct HeadTooLarge;
+
+impl std::fmt::Display for HeadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "accept request head (lines are limited to {} bytes, and the head to {} bytes)",
+            MAX_LINE_SIZE, MAX_HEAD_SIZE
+        )
+    }
+}
+
+impl std::error::Error for HeadTooLarge {}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+
+    /// The `Origin` header, which browsers send with cross-origin requests.
+    pub origin: Option<String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: String) -> Response {
+        Response { status, body }
+    }
+
+    /// A response whose body is a JSON object with an `error` message.
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::new(status, json!({ "error": message }).to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "",
+    }
+}
+
+/// Read a line of the request head into `line`, keeping the head within the
+/// size limits. `head_size` is the size of the head so far. Returns the size
+/// of the line, which is 0 at the end of the input.
+fn read_head_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    head_size: &mut usize,
+) -> Result<usize> {
+    line.clear();
+    let limit = MAX_LINE_SIZE.min(MAX_HEAD_SIZE - *head_size);
+    let len = reader
+        .take(limit as u64)
+        .read_line(line)
+        .context("read request")?;
+    if len == limit && !line.ends_with('\n') {
+        return Err(HeadTooLarge.into());
+    }
+    *head_size += len;
+    Ok(len)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut line = String::new();
+    let mut head_size = 0;
+    read_head_line(reader, &mut line, &mut head_size)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => bail!("parse request line"),
+    };
+
+    let mut content_length = 0;
+    let mut origin = None;
+    loop {
+        if read_head_line(reader, &mut line, &mut head_size)? == 0 {
+            bail!("read request headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().context("parse Content-Length")?;
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        bail!("accept request body of {} bytes", content_length);
+    }
+
+    // The body is read into a growing buffer, so a bogus `Content-Length`
+    // doesn't cause a large allocation.
+    let mut body = vec![];
+    reader
+        .take(content_length as u64)
+        .read_to_end(&mut body)
+        .context("read request body")?;
+    if body.len() < content_length {
+        bail!("read request body (it ended early)");
+    }
+    Ok(Request {
+        method,
+        path,
+        body,
+        origin,
+    })
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    response: &Response,
+    allowed_origin: Option<&str>,
+) -> std::io::Result<()> {
+    // The profiler runs on another origin, so the response must allow that.
+    let cors = match allowed_origin {
+        Some(origin) => format!(
+            "Access-Control-Allow-Origin: {}\r\n\
+             Access-Control-Allow-Methods: POST, OPTIONS\r\n\
+             Access-Control-Allow-Headers: Content-Type\r\n\
+             Vary: Origin\r\n",
+            origin
+        ),
+        None => String::new(),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n\
+         {}\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        response.status,
+        reason(response.status),
+        cors,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+/// Read one request from `stream`, pass it to `handle`, and write the
+/// response. CORS preflight requests are answered without calling `handle`,
+/// and requests from origins not in `allowed_origins` are rejected.
+pub fn handle_connection(
+    mut stream: TcpStream,
+    allowed_origins: &[String],
+    handle: &mut impl FnMut(&Request) -> Response,
+) -> Result<()> {
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+        .context("set timeouts")?;
+    let mut reader = BufReader::new(stream.try_clone().context("read request")?);
+    let request = read_request(&mut reader);
+    let origin = match &request {
+        Ok(request) => request.origin.as_deref(),
+        Err(_) => None,
+    };
+    let allowed_origin = origin.filter(|origin| allowed_origins.iter().any(|o| o == origin));
+    let response = match &request {
+        Ok(_) if origin.is_some() && allowed_origin.is_none() => {
+            Response::error(403, "origin not allowed")
+        }
+        Ok(request) if request.method == "OPTIONS" => Response::new(204, String::new()),
+        Ok(request) => handle(request),
+        Err(err) => {
+            let status = if err.chain().any(|cause| cause.is::<HeadTooLarge>()) {
+                431
+            } else {
+                400
+            };
+            Response::error(status, &format!("failed to {:#}", err))
+        }
+    };
+    write_response(&mut stream, &response, allowed_origin).context("write response")
+}
+
+/// Serve requests on `listener` forever.
+pub fn serve(
+    listener: TcpListener,
+    allowed_origins: &[String],
+    level: Level,
+    mut handle: impl FnMut(&Request) -> Response,
+) {
+    for stream in listener.incoming() {
+        let result = stream
+            .context("accept connection")
+            .and_then(|stream| handle_connection(stream, allowed_origins, &mut handle));
+        if let Err(err) = result {
+            diag::warning(level, format!("failed to {:#}", err));
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Crash signatures for fixed stacks, in the style of Socorro's, e.g.
+//! `[@ moz_xmalloc | mozilla::dom::Foo<...>::Bar]`.
+//!
+//! Each frame is normalized, by removing parameters, template arguments and
+//! Rust hashes. Leading frames that match an "irrelevant" pattern, such as
+//! `abort`, are skipped. Then frames are added to the signature up to and
+//! including the first one that doesn't match a "prefix" pattern, such as
+//! `moz_xmalloc`.
+
+use crate::demangle::{self, Style};
+use regex::Regex;
+
+/// The frames skipped at the start of a stack. Patterns must match the whole
+/// normalized function name.
+const IRRELEVANT: &[&str] = &[
+    "abort",
+    "__GI_abort",
+    "raise",
+    "__GI_raise",
+    "__pthread_kill.*",
+    "pthread_kill",
+    "mozalloc_abort",
+    "MOZ_Crash",
+    "MOZ_CrashPrintf",
+    "MOZ_CrashSequence",
+    "MOZ_ReportAssertionFailure",
+    "NS_DebugBreak",
+    "RustMozCrash",
+    "rust_panic",
+    "core::panicking::.*",
+    "std::panicking::.*",
+    "std::sys_common::backtrace::.*",
+    "KiUserExceptionDispatcher",
+    "RtlRaiseException",
+    "_CxxThrowException",
+];
+
+/// The frames that are kept in a signature along with the frame after them.
+const PREFIX: &[&str] = &[
+    "mozalloc_handle_oom",
+    "moz_xmalloc",
+    "moz_xcalloc",
+    "moz_xrealloc",
+    "malloc",
+    "calloc",
+    "realloc",
+    "free",
+    "memcpy",
+    "memmove",
+    "memset",
+    "strlen",
+    "operator new",
+    r"operator new\[\]",
+    "mozilla::detail::InvalidArrayIndex_CRASH",
+    r"nsTArray_base<\.\.\.>::.*",
+    r"nsTArray_Impl<\.\.\.>::.*",
+    "core::option::expect_failed",
+    "core::result::unwrap_failed",
+    "core::slice::index::.*",
+];
+
+/// Compile patterns that must match a whole normalized function name.
+pub fn compile(patterns: &[&str]) -> Result<Vec<Regex>, regex::Error> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+        .collect()
+}
+
+/// Normalize a function name for a signature, whether it's mangled or not.
+pub fn normalize(name: &str) -> String {
+    // Remove any offset, e.g. `foo + 0x12` or `foo+0x12`.
+    let name = match name.rsplit_once('+') {
+        Some((name, offset)) if offset.trim_start().starts_with("0x") => name.trim_end(),
+        _ => name,
+    };
+    demangle::format_name(name, Style::Collapsed, true)
+}
+
+/// The frame for a signature when the function is unknown, e.g.
+/// `libxul.so@0x1234`. `offset` is in hex, with or without `0x` and leading
+/// zeros, so that offsets too large to parse can be used as they are.
+pub fn unresolved(module: Option<&str>, offset: &str) -> String {
+    let module = module.map_or("", |module| {
+        module.rsplit(['/', '\\']).next().unwrap_or_default()
+    });
+    let offset = offset.strip_prefix("0x").unwrap_or(offset);
+    let offset = match offset.trim_start_matches('0') {
+        "" => "0",
+        offset => offset,
+    };
+    format!("{}@0x{}", module, offset.to_ascii_lowercase())
+}
+
+pub struct SignatureInfo {
+    pub irrelevant: Vec<Regex>,
+    pub prefix: Vec<Regex>,
+
+    /// The normalized frames of the current stack.
+    frames: Vec<String>,
+}
+
+impl Default for SignatureInfo {
+    fn default() -> SignatureInfo {
+        SignatureInfo {
+            irrelevant: compile(IRRELEVANT).unwrap(),
+            prefix: compile(PREFIX).unwrap(),
+            frames: vec![],
+        }
+    }
+}
+
+impl SignatureInfo {
+    /// Add the next frame of the current stack.
+    pub fn push(&mut self, frame: String) {
+        self.frames.push(frame);
+    }
+
+    /// Get the signature of the current stack, if it has any frames, and
+    /// start a new stack.
+    pub fn take(&mut self) -> Option<String> {
+        let frames = std::mem::take(&mut self.frames);
+        let matches =
+            |patterns: &[Regex], frame: &str| patterns.iter().any(|re| re.is_match(frame));
+
+        // If every frame is irrelevant, use the first one.
+        let start = frames
+            .iter()
+            .position(|frame| !matches(&self.irrelevant, frame))
+            .unwrap_or(0);
+        let mut signature = vec![];
+        for frame in &frames[start..] {
+            signature.push(frame.as_str());
+            if !matches(&self.prefix, frame) {
+                break;
+            }
+        }
+        if signature.is_empty() {
+            None
+        } else {
+            Some(format!("[@ {}]", signature.join(" | ")))
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reading of the source server (`srcsrv`) stream found in PDB files, which
+//! maps local source paths on the build machine to their location in version
+//! control. Firefox's release automation writes a stream like this:
+//!
+//! ```text
+//! SRCSRV: ini ------------------------------------------------
+//! VERSION=2
+//! INDEXVERSION=2
+//! VERCTRL=http
+//! SRCSRV: variables ------------------------------------------
+//! SRCSRVVERCTRL=http
+//! HTTP_EXTRACT_TARGET=%hgserver%/raw-file/%var3%/%var2%
+//! HGSERVER=https://hg.mozilla.org/mozilla-central
+//! SRCSRVTRG=%http_extract_target%
+//! SRCSRV: source files ---------------------------------------
+//! c:\builds\worker\checkouts\gecko\dom\base\Element.cpp*dom/base/Element.cpp*<rev>
+//! SRCSRV: end ------------------------------------------------
+//! ```
+
+use fxhash::FxHashMap;
+
+/// Variable expansion can recurse; this stops malformed streams from causing
+/// infinite recursion.
+const MAX_EXPANSION_DEPTH: u32 = 16;
+
+#[derive(Default)]
+pub struct SrcSrv {
+    /// The variables, with lowercase names because variable names are
+    /// case-insensitive.
+    vars: FxHashMap<String, String>,
+
+    /// The source files. Each key is a lowercase local path, because Windows
+    /// paths are case-insensitive. Each value holds the fields of the line,
+    /// which are `%var1%`, `%var2%`, etc.
+    files: FxHashMap<String, Vec<String>>,
+}
+
+impl SrcSrv {
+    /// Parse the text of a `srcsrv` stream.
+    pub fn parse(text: &str) -> SrcSrv {
+        enum Section {
+            Other,
+            Variables,
+            SourceFiles,
+        }
+
+        let mut srcsrv = SrcSrv::default();
+        let mut section = Section::Other;
+        for line in text.lines() {
+            let line = line.trim_end();
+            if let Some(header) = line.strip_prefix("SRCSRV: ") {
+                section = if header.starts_with("variables") {
+                    Section::Variables
+                } else if header.starts_with("source files") {
+                    Section::SourceFiles
+                } else {
+                    Section::Other
+                };
+                continue;
+            }
+            match section {
+                Section::Variables => {
+                    if let Some((name, value)) = line.split_once('=') {
+                        srcsrv
+                            .vars
+                            .insert(name.trim().to_ascii_lowercase(), value.to_string());
+                    }
+                }
+                Section::SourceFiles => {
+                    let fields: Vec<_> = line.split('*').map(str::to_string).collect();
+                    if fields.len() > 1 {
+                        srcsrv.files.insert(fields[0].to_ascii_lowercase(), fields);
+                    }
+                }
+                Section::Other => {}
+            }
+        }
+        srcsrv
+    }
+
+    /// Get the version control location of `path`, if it is in the stream.
+    /// Locations in Mercurial repositories are given in the form used by
+    /// Firefox's Breakpad symbol files, i.e.
+    /// `hg:<host>/<repo>:<path>:<rev>`, so they can be treated the same way.
+    /// Other locations are given as the expansion of `SRCSRVTRG`, which is
+    /// typically a URL.
+    pub fn location(&self, path: &str) -> Option<String> {
+        let fields = self.files.get(&path.to_ascii_lowercase())?;
+        let target = self.expand("%srcsrvtrg%", fields, 0);
+        if target.is_empty() || target.contains('%') {
+            // The expansion failed.
+            return None;
+        }
+        Some(SrcSrv::hg_location(&target).unwrap_or(target))
+    }
+
+    /// Convert a URL of the form `https://<host>/<repo>/raw-file/<rev>/<path>`
+    /// to the form `hg:<host>/<repo>:<path>:<rev>`.
+    fn hg_location(url: &str) -> Option<String> {
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))?;
+        let (repo, rest) = rest.split_once("/raw-file/")?;
+        let (rev, path) = rest.split_once('/')?;
+        if rev.is_empty() || !rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(format!("hg:{}:{}:{}", repo, path, rev))
+    }
+
+    /// Expand the variables in `string`. `%varN%` refers to the Nth field of
+    /// a source file line, and other `%name%` references refer to the
+    /// variables section. The `%fnvar%`, `%fnbksl%` and `%fnfile%` functions
+    /// are also supported.
+    fn expand(&self, string: &str, fields: &[String], depth: u32) -> String {
+        if depth > MAX_EXPANSION_DEPTH {
+            return string.to_string();
+        }
+
+        let mut out = String::new();
+        let mut rest = string;
+        while let Some(start) = rest.find('%') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = match after.find('%') {
+                Some(end) => end,
+                None => {
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+            let name = after[..end].to_ascii_lowercase();
+            rest = &after[end + 1..];
+
+            // A function call, e.g. `%fnbksl%(%var2%)`.
+            if name.starts_with("fn") && rest.starts_with('(') {
+                if let Some(close) = rest.find(')') {
+                    let arg = self.expand(&rest[1..close], fields, depth + 1);
+                    rest = &rest[close + 1..];
+                    match name.as_str() {
+                        "fnvar" => match self.var(&arg.to_ascii_lowercase(), fields) {
+                            Some(value) => out.push_str(&self.expand(&value, fields, depth + 1)),
+                            None => out.push_str(&format!("%{}%", arg)),
+                        },
+                        "fnbksl" => out.push_str(&arg.replace('/', "\\")),
+                        "fnfile" => out.push_str(arg.rsplit(['/', '\\']).next().unwrap_or("")),
+                        _ => out.push_str(&arg),
+                    }
+                    continue;
+                }
+            }
+
+            match self.var(&name, fields) {
+                Some(value) => out.push_str(&self.expand(&value, fields, depth + 1)),
+                None => out.push_str(&format!("%{}%", name)),
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Get the value of a variable. `name` must be lowercase.
+    fn var(&self, name: &str, fields: &[String]) -> Option<String> {
+        if let Some(n) = name.strip_prefix("var") {
+            if let Ok(n) = n.parse::<usize>() {
+                let field = n.checked_sub(1).and_then(|i| fields.get(i));
+                return Some(field.cloned().unwrap_or_default());
+            }
+        }
+        self.vars.get(name).cloned()
+    }
+}
@@ -0,0 +1,134 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Output templates for fixed frames, such as
+//! `{prefix}{function} ({location}){suffix}`. Each `{name}` is replaced by
+//! the value of that field, and `{{` and `}}` are literal braces. A
+//! different template can be used for each resolution level.
+
+use crate::diag::Resolution;
+use fxhash::FxHashMap;
+
+/// The fields that can be used in a frame template.
+pub const FIELDS: &[&str] = &[
+    "prefix",
+    "frameno",
+    "function",
+    "short_function",
+    "location",
+    "file",
+    "basename",
+    "line",
+    "column",
+    "module",
+    "offset",
+    "function_offset",
+    "source",
+    "suffix",
+];
+
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse a template whose fields must be in `fields`.
+    pub fn parse(template: &str, fields: &[&str]) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or_else(|| format!("unclosed `{{` in template `{}`", template))?;
+                    if !fields.contains(&name) {
+                        return Err(format!(
+                            "unknown field `{{{}}}` in template `{}`",
+                            name, template
+                        ));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(name.to_string()));
+                    chars = rest.chars();
+                }
+                '}' => return Err(format!("unmatched `}}` in template `{}`", template)),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Fill in the template. Fields without a value are left empty.
+    pub fn render(&self, values: &FxHashMap<&str, String>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Field(name) => {
+                    if let Some(value) = values.get(name.as_str()) {
+                        out.push_str(value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A template for each resolution level, any of which may be absent.
+#[derive(Default)]
+pub struct Templates {
+    pub fixed: Option<Template>,
+    pub name_only: Option<Template>,
+    pub unresolved: Option<Template>,
+}
+
+impl Templates {
+    pub fn get(&self, resolution: Resolution) -> Option<&Template> {
+        match resolution {
+            Resolution::Fixed => self.fixed.as_ref(),
+            Resolution::NameOnly => self.name_only.as_ref(),
+            Resolution::Unresolved => self.unresolved.as_ref(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fixed.is_none() && self.name_only.is_none() && self.unresolved.is_none()
+    }
+
+    /// Set the template for the level named `level`, which is `fixed`,
+    /// `name_only` or `unresolved`.
+    pub fn set(&mut self, level: &str, template: &str) -> Result<(), String> {
+        let slot = match level {
+            "fixed" => &mut self.fixed,
+            "name_only" => &mut self.name_only,
+            "unresolved" => &mut self.unresolved,
+            _ => return Err(format!("unknown resolution level `{}`", level)),
+        };
+        *slot = Some(Template::parse(template, FIELDS)?);
+        Ok(())
+    }
+}
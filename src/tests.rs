@@ -4,6 +4,41 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::*;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A temporary directory for a test, which is removed when it is dropped,
+/// even if the test panics.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        // Tests run in parallel threads, so the process ID isn't enough.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "fix-stacks-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
 
 #[test]
 fn test_linux() {
@@ -160,6 +195,313 @@ fn test_windows() {
     outside(0xfffffff); // A very high address.
 }
 
+#[test]
+fn test_srcsrv() {
+    // A `srcsrv` stream like those in PDB files produced by Firefox's release
+    // automation, plus an extra file that uses a different target.
+    let rev = "04c31e994f29e72dd81a7340100d12f67e48a5b4";
+    let text = format!(
+        "SRCSRV: ini ------------------------------------------------\r\n\
+         VERSION=2\r\n\
+         INDEXVERSION=2\r\n\
+         VERCTRL=http\r\n\
+         SRCSRV: variables ------------------------------------------\r\n\
+         SRCSRVVERCTRL=http\r\n\
+         HTTP_EXTRACT_TARGET=%hgserver%/raw-file/%var3%/%var2%\r\n\
+         HGSERVER=https://hg.mozilla.org/mozilla-central\r\n\
+         S3_TARGET=https://gecko-generated-sources.s3.amazonaws.com/%var2%\r\n\
+         SRCSRVTRG=%fnvar%(%var4%)\r\n\
+         SRCSRV: source files ---------------------------------------\r\n\
+         c:\\builds\\gecko\\dom\\Element.cpp*dom/Element.cpp*{rev}*HTTP_EXTRACT_TARGET\r\n\
+         c:\\builds\\obj\\Gen.cpp*abc/Gen.cpp**S3_TARGET\r\n\
+         SRCSRV: end ------------------------------------------------\r\n"
+    );
+    let srcsrv = SrcSrv::parse(&text);
+
+    // Mercurial locations are converted to the Breakpad form, and local path
+    // matching is case-insensitive.
+    let hg = format!("hg:hg.mozilla.org/mozilla-central:dom/Element.cpp:{}", rev);
+    assert_eq!(
+        srcsrv.location("c:\\builds\\gecko\\dom\\Element.cpp"),
+        Some(hg.clone())
+    );
+    assert_eq!(
+        srcsrv.location("C:\\BUILDS\\gecko\\dom\\element.cpp"),
+        Some(hg.clone())
+    );
+    assert_eq!(
+        VcsPath::parse(&hg, &VcsScheme::builtin()).map(|vcs_path| vcs_path.path),
+        Some("dom/Element.cpp")
+    );
+
+    // Without `--source-url`, PDB paths keep their revision, but Breakpad
+    // paths don't.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.pdb_info.srcsrv = true;
+    assert_eq!(
+        fixer.raw_location(&hg, 10, &Source::Pdb(PathBuf::from("xul.pdb"))),
+        format!("dom/Element.cpp@{}:10", rev)
+    );
+    assert_eq!(
+        fixer.raw_location(&hg, 10, &Source::Breakpad(PathBuf::from("xul.sym"))),
+        "dom/Element.cpp:10"
+    );
+
+    // Other locations are URLs.
+    assert_eq!(
+        srcsrv.location("c:\\builds\\obj\\Gen.cpp").as_deref(),
+        Some("https://gecko-generated-sources.s3.amazonaws.com/abc/Gen.cpp")
+    );
+
+    // Files not in the stream are left alone.
+    assert_eq!(srcsrv.location("c:\\builds\\gecko\\Other.cpp"), None);
+
+    // Variables that can't be expanded mean there is no location.
+    let srcsrv = SrcSrv::parse(
+        "SRCSRV: variables -\nSRCSRVTRG=%nope%/%var2%\nSRCSRV: source files -\na.cpp*a\n",
+    );
+    assert_eq!(srcsrv.location("a.cpp"), None);
+
+    // Remapping interned strings.
+    let mut interner = Interner::default();
+    let a = interner.intern("a".to_string());
+    let b = interner.intern("b".to_string());
+    interner.remap(|s| {
+        if s == "a" {
+            Some("x".to_string())
+        } else {
+            None
+        }
+    });
+    assert_eq!(interner.get(a), "x");
+    assert_eq!(interner.get(b), "b");
+}
+
+#[test]
+fn test_windows_exports() {
+    // `pe-exports.dll` has no PDB file, so the function names come from the
+    // export table and the function bounds come from the `.pdata` section,
+    // which are as follows. (See `tests/README.md` for details on how this
+    // file was generated.)
+    //
+    //   EXPORT 0x1000 exported_A
+    //   EXPORT 0x1010 exported_B
+    //
+    //   PDATA 0x1000..0x1008
+    //   PDATA 0x1008..0x1010 (the unexported `internal` function)
+    //   PDATA 0x1010..0x1018
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+
+    let mut func = |name, addr, offset| {
+        let line = format!("#00: ???[tests/pe-exports.dll +0x{:x}]", addr);
+        let line = fixer.fix(line);
+        let expected = if name == "???" {
+            format!("#00: ??? (tests/pe-exports.dll + 0x{:x})", addr)
+        } else {
+            format!("#00: pe-exports!{} + 0x{:x}", name, offset)
+        };
+        assert_eq!(line, expected);
+    };
+    func("exported_A", 0x1000, 0x0);
+    func("exported_A", 0x1007, 0x7);
+    func("???", 0x1008, 0); // Within `internal`.
+    func("???", 0x100f, 0); // Within `internal`.
+    func("exported_B", 0x1010, 0x0);
+    func("exported_B", 0x1017, 0x7);
+    func("???", 0x1018, 0); // One byte past the end of `exported_B`.
+    func("???", 0x0, 0); // A very low address.
+
+    // In JSON mode, the module name is escaped.
+    let dir = TempDir::new("pe-exports-json");
+    let dll = dir.join("pe\"exports.dll");
+    fs::copy("tests/pe-exports.dll", &dll).unwrap();
+    let mut fixer = Fixer::new(JsonMode::Yes, None, None);
+    let in_file_name = Fixer::json_escape(dll.to_str().unwrap());
+    assert_eq!(
+        fixer.fix(format!("#00: ???[{} +0x1004]", in_file_name)),
+        "#00: pe\\\"exports!exported_A + 0x4"
+    );
+
+    // `pe-exports-debug.dll` is the same, but refers to a PDB file. If there
+    // is none, the export table is used quietly.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    assert_eq!(
+        fixer.fix("#00: ???[tests/pe-exports-debug.dll +0x1004]".to_string()),
+        "#00: pe-exports-debug!exported_A + 0x4"
+    );
+    assert_eq!(fixer.stats.mismatches, 0);
+
+    // A PDB file with the wrong ID is counted as a mismatch, even though the
+    // export table is still used.
+    let dir = TempDir::new("pe-exports");
+    let dll = dir.join("pe-exports-debug.dll");
+    fs::copy("tests/pe-exports-debug.dll", &dll).unwrap();
+    fs::write(dir.join("pe-exports-debug.pdb"), b"not a PDB file").unwrap();
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    assert_eq!(
+        fixer.fix(format!("#00: ???[{} +0x1004]", dll.display())),
+        "#00: pe-exports-debug!exported_A + 0x4"
+    );
+    assert_eq!(fixer.stats.mismatches, 1);
+    assert!(fixer.stats.failures.is_empty());
+}
+
+#[test]
+fn test_summary() {
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    for line in [
+        "#00: ???[tests/example-linux +0x1130]",
+        "#01: ???[tests/example-linux +0x1160]",
+        "#02: ???[tests/pe-exports.dll +0x1000]",
+        "#03: ???[tests/example-linux +0x0]",
+        "#04: ???[tests/no-such-file +0x10]",
+        "#05: ???[tests/no-such-file +0x20]",
+        "no frame here",
+    ] {
+        fixer.fix(line.to_string());
+    }
+
+    let stats = &fixer.stats;
+    assert_eq!(
+        (stats.frames, stats.fixed, stats.name_only, stats.unresolved),
+        (6, 2, 1, 3)
+    );
+    let failures: Vec<_> = stats.failures.keys().map(String::as_str).collect();
+    assert_eq!(failures, ["tests/no-such-file"]);
+    assert!(stats.failures["tests/no-such-file"].starts_with("failed to read: "));
+
+    // The source of the debug info is recorded for verbose mode.
+    assert!(matches!(
+        fixer.file_infos["tests/example-linux"].source,
+        Source::Native
+    ));
+    assert!(matches!(
+        fixer.file_infos["tests/pe-exports.dll"].source,
+        Source::Exports
+    ));
+}
+
+#[test]
+fn test_fail_on() {
+    let parse = |spec| FailOn::parse(spec).unwrap();
+    assert_eq!(parse("resolved-below=90"), FailOn::ResolvedBelow(90.0));
+    assert!(FailOn::parse("resolved-below=101").is_err());
+    assert!(FailOn::parse("resolved-below").is_err());
+    assert!(FailOn::parse("everything").is_err());
+
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    let fail_on = [parse("unresolved"), parse("resolved-below=90")];
+    assert_eq!(fixer.stats.exit_status(&fail_on, Level::Normal), 0);
+    for _ in 0..9 {
+        fixer.fix("#00: ???[tests/example-linux +0x1130]".to_string());
+    }
+    assert_eq!(fixer.stats.exit_status(&fail_on, Level::Normal), 0);
+    fixer.fix("#00: ???[tests/example-linux +0x0]".to_string());
+    assert_eq!(fixer.stats.exit_status(&fail_on, Level::Normal), 4);
+    assert_eq!(fixer.stats.exit_status(&fail_on[1..], Level::Normal), 0);
+    fixer.fix("#00: ???[tests/example-linux +0x0]".to_string());
+    assert_eq!(fixer.stats.exit_status(&fail_on[1..], Level::Normal), 5);
+
+    // The lowest exit status is used if several conditions hold.
+    assert_eq!(fixer.stats.exit_status(&FailOn::STRICT, Level::Normal), 4);
+    fixer.fix("#00: ???[tests/no-such-file +0x0]".to_string());
+    assert_eq!(fixer.stats.exit_status(&FailOn::STRICT, Level::Normal), 2);
+
+    // Errors in JSON files are returned, not fatal, so the summary and exit
+    // status still follow them.
+    let err = fix_json_file("tests/no-such-file.json", |_| Ok(())).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("failed to read `tests/no-such-file.json`\nfix-stacks: "));
+    let err = fix_json_file("tests/README.md", |_| Ok(())).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("failed to parse `tests/README.md`\nfix-stacks: "));
+}
+
+#[test]
+fn test_pdb_search() {
+    let mut pdb_info = PdbInfo {
+        dirs: vec![PathBuf::from("pdbs")],
+        stores: vec![],
+        srcsrv: false,
+    };
+    pdb_info.add_symbol_path(
+        "srv*c:\\symcache*https://symbols.example.com; SRV*store2*http://x.com ;c:\\flat;;",
+    );
+    assert_eq!(pdb_info.dirs, [Path::new("pdbs"), Path::new("c:\\flat")]);
+    assert_eq!(
+        pdb_info.stores,
+        [Path::new("c:\\symcache"), Path::new("store2")]
+    );
+
+    // The recorded path is a Windows path, which must be split on backslashes
+    // on all platforms.
+    let debug_id = DebugId::from_breakpad("0D90B398AD8C47A5AE47DD31625B8F902").unwrap();
+    let candidates = pdb_info.candidates(
+        "bin/example-windows.exe",
+        "c:\\Users\\njn\\example-windows.pdb",
+        debug_id,
+    );
+    let id = "0D90B398AD8C47A5AE47DD31625B8F902";
+    let expected: Vec<PathBuf> = vec![
+        "c:\\Users\\njn\\example-windows.pdb".into(),
+        "bin/example-windows.pdb".into(),
+        "pdbs/example-windows.pdb".into(),
+        "c:\\flat/example-windows.pdb".into(),
+        [
+            "c:\\symcache",
+            "example-windows.pdb",
+            id,
+            "example-windows.pdb",
+        ]
+        .iter()
+        .collect(),
+        ["store2", "example-windows.pdb", id, "example-windows.pdb"]
+            .iter()
+            .collect(),
+    ];
+    assert_eq!(candidates, expected);
+
+    // Duplicate paths are only tried once.
+    let candidates = PdbInfo::default().candidates(
+        "tests/example-windows.exe",
+        "tests/example-windows.pdb",
+        debug_id,
+    );
+    assert_eq!(candidates, [Path::new("tests/example-windows.pdb")]);
+
+    // A missing PDB file leaves the frame unfixed.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.pdb_info = pdb_info;
+    assert_eq!(
+        fixer.fix("#00: ???[tests/example-windows.exe +0x6bc0]".to_string()),
+        "#00: ??? (tests/example-windows.exe + 0x6bc0)"
+    );
+
+    // A corrupt PDB file is reported like a mismatched one, and doesn't stop
+    // the search.
+    let dir = TempDir::new("pdb");
+    let bin_file = dir.join("example-windows.exe");
+    fs::copy("tests/example-windows.exe", &bin_file).unwrap();
+    fs::write(dir.join("example-windows.pdb"), b"not a PDB file").unwrap();
+    let data = fs::read(&bin_file).unwrap();
+    let err = match Fixer::build_file_info_pdb(
+        bin_file.to_str().unwrap(),
+        &data,
+        &PdbInfo::default(),
+        false,
+    ) {
+        Ok(_) => panic!("a corrupt PDB file was used"),
+        Err(err) => err,
+    };
+    assert!(err.chain().any(|e| e.is::<IdMismatch>()));
+    assert!(format!("{:#}", err).contains(&format!(
+        "`{}` can't be parsed",
+        dir.join("example-windows.pdb").display()
+    )));
+}
+
 #[test]
 #[ignore] // https://github.com/mozilla/fix-stacks/issues/45
 fn test_mac() {
@@ -265,6 +607,34 @@ fn test_mac() {
     func("duplicate", 0xf38, true, "mac-lib1.c:10");
 }
 
+#[test]
+fn test_mac_symtab() {
+    // This tests the fallback used when the object files referenced by a
+    // Mach-O binary are unavailable. Function bounds come from
+    // `LC_FUNCTION_STARTS`, and names from the symbol table, so there are no
+    // file names or line numbers.
+    let data = fs::read("tests/mac-multi").unwrap();
+    let macho = Fixer::macho(&data).unwrap();
+    let file_info = Fixer::build_file_info_macho_symtab(&macho).unwrap();
+
+    let func = |name, addr| {
+        let func_info = file_info.func_info(addr).unwrap();
+        assert_eq!(func_info.demangled_name(&NameOptions::default()), name);
+        assert!(func_info.line_info(addr).is_none());
+    };
+    func("main", 0xd70);
+    func("main", 0xd9d);
+    func("duplicate", 0xdb3);
+    func("fat_B", 0xddc);
+    func("fat_A", 0xe19);
+    func("lib1_B", 0xe50);
+    func("lib2_A", 0xf1e);
+
+    // Addresses outside `__text` have no function.
+    assert!(file_info.func_info(0x0).is_none());
+    assert!(file_info.func_info(0xfffffff).is_none());
+}
+
 #[test]
 fn test_linux_breakpad() {
     // The breakpad symbols debug info within `bpsyms/example-linux/` is as
@@ -338,11 +708,250 @@ fn test_linux_breakpad() {
     outside(0xfffffff); // A very high address.
 }
 
+#[test]
+fn test_vcs_paths() {
+    let schemes = VcsScheme::builtin();
+    let rev = "04c31e994f29e72dd81a7340100d12f67e48a5b4";
+
+    // Returns the vcs, repo, path and rev, or nothing if parsing fails.
+    let parse = |file_name: &str| -> Vec<String> {
+        let file_name = file_name.replace("<rev>", rev);
+        VcsPath::parse(&file_name, &schemes)
+            .map(|p| [p.vcs, p.repo, p.path, p.rev].map(str::to_string).to_vec())
+            .unwrap_or_default()
+    };
+
+    // The built-in schemes.
+    assert_eq!(
+        parse("hg:hg.mozilla.org/mozilla-central:dom/Element.cpp:<rev>"),
+        [
+            "hg",
+            "hg.mozilla.org/mozilla-central",
+            "dom/Element.cpp",
+            rev
+        ]
+    );
+    assert_eq!(
+        parse("hg:hg.example.org/fork:a/b.cpp:<rev>"),
+        ["hg", "hg.example.org/fork", "a/b.cpp", rev]
+    );
+    assert_eq!(
+        parse("git:github.com/rust-lang/rust:library/core/src/lib.rs:<rev>"),
+        [
+            "git",
+            "github.com/rust-lang/rust",
+            "library/core/src/lib.rs",
+            rev
+        ]
+    );
+    assert_eq!(
+        parse("s3:gecko-generated-sources:0123abcd/ipc/PFoo.cpp:"),
+        ["s3", "gecko-generated-sources", "0123abcd/ipc/PFoo.cpp", ""]
+    );
+
+    // Paths containing colons.
+    assert_eq!(
+        parse("git:github.com/org/repo:c:\\src\\a.cpp:<rev>"),
+        ["git", "github.com/org/repo", "c:\\src\\a.cpp", rev]
+    );
+
+    // Things that aren't VCS paths.
+    assert!(parse("c:\\src\\a.cpp").is_empty());
+    assert!(parse("/src/a.cpp").is_empty());
+    assert!(parse("svn:svn.example.org:a.cpp:<rev>").is_empty());
+    assert!(parse("hg:hg.mozilla.org/mozilla-central:a.cpp:xyz").is_empty());
+    assert!(parse("hg:hg.mozilla.org/mozilla-central:a.cpp:").is_empty());
+    assert!(parse("s3:bucket:a.cpp:<rev>").is_empty());
+    assert!(parse("hg::a.cpp:<rev>").is_empty());
+    assert!(parse("hg:hg.mozilla.org/mozilla-central::<rev>").is_empty());
+
+    // Configured schemes.
+    let mut schemes = VcsScheme::builtin();
+    schemes.push(VcsScheme::from_spec("svn").unwrap());
+    schemes.push(VcsScheme::from_spec("gcs:none").unwrap());
+    assert_eq!(
+        VcsPath::parse(&format!("svn:svn.example.org:a.cpp:{}", rev), &schemes).map(|p| p.path),
+        Some("a.cpp")
+    );
+    assert_eq!(
+        VcsPath::parse("gcs:bucket:a.cpp:", &schemes).map(|p| p.path),
+        Some("a.cpp")
+    );
+    assert!(VcsScheme::from_spec("svn:hex").is_ok());
+    assert!(VcsScheme::from_spec("svn:sometimes").is_err());
+    assert!(VcsScheme::from_spec("c").is_err());
+    assert!(VcsScheme::from_spec("a/b").is_err());
+}
+
+#[test]
+fn test_path_map() {
+    // `.` and `..` components are always removed.
+    assert_eq!(pathmap::normalize("/a/./b/../c.cpp"), "/a/c.cpp");
+    assert_eq!(pathmap::normalize("./a.cpp"), "a.cpp");
+    assert_eq!(pathmap::normalize("../../a/../b.cpp"), "../../b.cpp");
+    assert_eq!(pathmap::normalize("/../a.cpp"), "/../a.cpp");
+    assert_eq!(pathmap::normalize("c:\\a\\..\\b.cpp"), "c:\\b.cpp");
+    assert_eq!(pathmap::normalize("a//b.cpp"), "a//b.cpp");
+
+    let mut path_map = PathMap::default();
+    path_map.add("/builds/worker/checkouts/gecko=").unwrap();
+    path_map
+        .add("/builds/worker/checkouts/gecko/obj-x86_64=objdir")
+        .unwrap();
+    path_map.add("c:\\mozilla-build\\src=").unwrap();
+    path_map.add("/home/njn/=/src/").unwrap();
+    let map = |path| path_map.map(path);
+
+    // The longest matching prefix wins.
+    assert_eq!(
+        map("/builds/worker/checkouts/gecko/dom/base/Element.cpp").as_deref(),
+        Some("dom/base/Element.cpp")
+    );
+    assert_eq!(
+        map("/builds/worker/checkouts/gecko/obj-x86_64/dist/include/a.h").as_deref(),
+        Some("objdir/dist/include/a.h")
+    );
+    assert_eq!(
+        map("/builds/worker/checkouts/gecko/obj-x86_64/../xpcom/a.cpp").as_deref(),
+        Some("xpcom/a.cpp")
+    );
+
+    // Prefixes only match whole components.
+    assert_eq!(
+        map("/builds/worker/checkouts/gecko2/a.cpp").as_deref(),
+        None
+    );
+    assert_eq!(map("/home/njn/a.cpp").as_deref(), Some("/src/a.cpp"));
+    assert_eq!(map("/home/njnx/a.cpp").as_deref(), None);
+
+    // Windows paths are matched case-insensitively and either separator, and
+    // get `/` separators.
+    assert_eq!(
+        map("C:/Mozilla-Build/src\\dom\\base\\Element.cpp").as_deref(),
+        Some("dom/base/Element.cpp")
+    );
+    assert_eq!(
+        map("c:\\mozilla-build\\src\\.\\a.cpp").as_deref(),
+        Some("a.cpp")
+    );
+    assert_eq!(map("/Home/njn/a.cpp").as_deref(), None);
+
+    // Unchanged paths and URLs.
+    assert_eq!(map("/usr/include/stdio.h").as_deref(), None);
+    assert_eq!(map("https://example.org/a/../b.cpp").as_deref(), None);
+
+    assert!(path_map.add("=foo").is_err());
+    assert!(path_map.add("foo").is_err());
+
+    // Paths in the output are mapped, but not those with version control
+    // info.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.path_map.add("/home/njn/moz/fix-stacks=").unwrap();
+    assert_eq!(
+        fixer.fix("#00: ???[tests/example-linux +0x1146]".to_string()),
+        "#00: main (tests/example.c:26)"
+    );
+
+    // `bpsyms/example-linux/` has version control locations. The path within
+    // them is mapped, but not when it's used for a link.
+    let fix = |source_link_info| {
+        let mut fixer = Fixer::new(
+            JsonMode::No,
+            Some(BreakpadInfo {
+                syms_dir: "tests/bpsyms".to_string(),
+            }),
+            None,
+        );
+        fixer.path_map.add("/home/njn/moz/fix-stacks=").unwrap();
+        fixer.source_link_info = source_link_info;
+        fixer.fix("#00: ???[tests/example-linux +0x1130]".to_string())
+    };
+    assert_eq!(fix(None), "#00: main [tests/example.c:24]");
+    assert_eq!(
+        fix(Some(SourceLinkInfo::new("searchfox", false))),
+        "#00: main [https://searchfox.org/autoland/rev/\
+         94d31f914f29e72dd81a8880100d12f67e48a5b0//home/njn/moz/fix-stacks/tests/example.c#24]"
+    );
+}
+
+#[test]
+fn test_source_links() {
+    // `bpsyms/example-linux/` refers to `example.c` within a Mercurial
+    // repository, so source locations can be turned into URLs.
+    let rev = "94d31f914f29e72dd81a8880100d12f67e48a5b0";
+    let path = "/home/njn/moz/fix-stacks/tests/example.c";
+    let file_name = format!("hg:hg.mozilla.org/integration/autoland:{}:{}", path, rev);
+    assert_eq!(
+        VcsPath::parse(&file_name, &VcsScheme::builtin()),
+        Some(VcsPath {
+            vcs: "hg",
+            repo: "hg.mozilla.org/integration/autoland",
+            path,
+            rev,
+        })
+    );
+
+    let fix = |json_mode, template, osc8, line: &str| {
+        let mut fixer = Fixer::new(
+            json_mode,
+            Some(BreakpadInfo {
+                syms_dir: "tests/bpsyms".to_string(),
+            }),
+            None,
+        );
+        fixer.source_link_info = Some(SourceLinkInfo::new(template, osc8));
+        fixer.fix(line.to_string())
+    };
+    let line = "#00: ???[tests/example-linux +0x1130]";
+
+    assert_eq!(
+        fix(JsonMode::No, "hg", false, line),
+        format!(
+            "#00: main [https://hg.mozilla.org/integration/autoland/file/{}/{}#l24]",
+            rev, path
+        )
+    );
+    assert_eq!(
+        fix(JsonMode::No, "searchfox", false, line),
+        format!(
+            "#00: main [https://searchfox.org/autoland/rev/{}/{}#24]",
+            rev, path
+        )
+    );
+    assert_eq!(
+        fix(JsonMode::No, "github", false, line),
+        format!(
+            "#00: main [https://github.com/integration/autoland/blob/{}/{}#L24]",
+            rev, path
+        )
+    );
+    assert_eq!(
+        fix(JsonMode::No, "{vcs} {host} {repo_name} {line}", false, line),
+        "#00: main [hg hg.mozilla.org autoland 24]"
+    );
+    assert_eq!(
+        fix(JsonMode::No, "hg", true, line),
+        format!(
+            "#00: main [\x1b]8;;https://hg.mozilla.org/integration/autoland/file/{}/{}#l24\x1b\\{}:24\x1b]8;;\x1b\\]",
+            rev, path, path
+        )
+    );
+
+    // In JSON mode, the escape characters must be escaped.
+    assert_eq!(
+        fix(JsonMode::Yes, "{line}", true, line),
+        format!(
+            "#00: main [\\u001b]8;;24\\u001b\\\\{}:24\\u001b]8;;\\u001b\\\\]",
+            path
+        )
+    );
+}
+
 #[test]
 fn test_linux_breakpad_fallback() {
     // The breakpad symbols debug info within `bpsyms/` is missing in this
     // test. This verifies that we fall back to using native debug information
-    // correctly in this scenario.
+    // correctly in this scenario, and that the brackets show it.
     //
     // The native debug info within `example-linux-fallback` is as follows. (See
     // `tests/README.md` for details on how these lines were generated.)
@@ -381,7 +990,7 @@ fn test_linux_breakpad_fallback() {
         assert_eq!(
             line,
             format!(
-                "#00: {} [/home/gsvelto/projects/fix-stacks/tests/example.c:{}]",
+                "#00: {} (/home/gsvelto/projects/fix-stacks/tests/example.c:{})",
                 name, linenum
             )
         );
@@ -399,13 +1008,55 @@ fn test_linux_breakpad_fallback() {
     let mut outside = |addr| {
         let line = format!("#00: ???[tests/example-linux-fallback +0x{:x}]", addr);
         let line_actual = fixer.fix(line);
-        let line_expected = format!("#00: ??? [tests/example-linux-fallback + 0x{:x}]", addr);
+        let line_expected = format!("#00: ??? (tests/example-linux-fallback + 0x{:x})", addr);
         assert_eq!(line_expected, line_actual);
     };
     outside(0x0); // A very low address.
     outside(0xfffffff); // A very high address.
 }
 
+#[test]
+fn test_show_source() {
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        Some(BreakpadInfo {
+            syms_dir: "tests/bpsyms".to_string(),
+        }),
+        None,
+    );
+    fixer.show_source = true;
+    let mut fix = |line: &str| fixer.fix(line.to_string());
+    assert_eq!(
+        fix("#00: ???[tests/example-linux +0x1146]"),
+        "#00: main [/home/njn/moz/fix-stacks/tests/example.c:26] \
+         {breakpad: tests/bpsyms/example-linux/BE4E976C325246EE9D6B7847A670B2A90/\
+         example-linux.sym}"
+    );
+    assert_eq!(
+        fix("#00: ???[tests/example-linux-fallback +0x11f8]"),
+        "#00: main (/home/gsvelto/projects/fix-stacks/tests/example.c:24) {native}"
+    );
+    assert_eq!(
+        fix("#00: ???[tests/pe-exports.dll +0x1000]"),
+        "#00: pe-exports!exported_A + 0x0 {exports}"
+    );
+    // Nothing is added to unresolved frames.
+    assert_eq!(
+        fix("#00: ???[tests/example-linux-fallback +0x0]"),
+        "#00: ??? (tests/example-linux-fallback + 0x0)"
+    );
+
+    // With a template, the annotation starts the suffix.
+    fixer
+        .templates
+        .set("fixed", "{prefix}{function} at {basename}:{line}{suffix}")
+        .unwrap();
+    assert_eq!(
+        fixer.fix("#00: ???[tests/example-linux-fallback +0x11f8] etc.".to_string()),
+        "#00: main at example.c:24 {native} etc."
+    );
+}
+
 #[test]
 fn test_windows_breakpad() {
     // The breakpad symbols debug info within `bpsyms/example-windows.pdb/` is
@@ -472,6 +1123,372 @@ fn test_windows_breakpad() {
     outside(0xfffffff); // A very high address.
 }
 
+#[test]
+fn test_config() {
+    let config = Config::parse(
+        r#"
+        # Frames like `  3  libxul.so  0x1234`.
+        [[frame]]
+        pattern = '^(?P<prefix>\s*)(?P<frameno>\d+)\s+(?P<module>\S+)\s+0x(?P<offset>[0-9a-f]+)(?P<suffix>.*)$'
+        template = '{prefix}{frameno} {function} at {location} [{source}] {{{file}:{line}}}{suffix}'
+
+        # Frames like `at f (libxul.so:0x1234)`, with the default template
+        # except for unresolved frames.
+        [[frame]]
+        pattern = '^(?P<prefix>at )(?P<func>\S+) \((?P<module>[^:]+):(?P<offset>0x[0-9a-f]+)\)$'
+        template_unresolved = '{prefix}{function} (unresolved)'
+
+        # Frames like `jit 0x1234`, using the templates from `[template]`.
+        [[frame]]
+        pattern = '^jit (?P<address>0x[0-9a-f]+)$'
+
+        [template]
+        name_only = '{function} in {module}'
+        unresolved = 'unresolved {location}'
+        "#,
+    )
+    .unwrap();
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.templates = config.templates;
+    fixer.frame_patterns = config.frame_patterns;
+    let mut fix = |line: &str| fixer.fix(line.to_string());
+
+    assert_eq!(
+        fix("  3  tests/example-linux  0x1146 !"),
+        "  3 main at /home/njn/moz/fix-stacks/tests/example.c:26 [native] \
+         {/home/njn/moz/fix-stacks/tests/example.c:26} !"
+    );
+    assert_eq!(
+        fix("  4  tests/pe-exports.dll  0x1000"),
+        "  4 exported_A at tests/pe-exports.dll + 0x1000 [exports] {:}"
+    );
+    assert_eq!(
+        fix("  5  tests/example-linux  0x0"),
+        "  5 ??? at tests/example-linux + 0x0 [] {:}"
+    );
+    assert_eq!(
+        fix("at g (tests/example-linux:0x11bc)"),
+        "at g (/home/njn/moz/fix-stacks/tests/example.c:12)"
+    );
+    assert_eq!(
+        fix("at input (tests/example-linux:0x0)"),
+        "at input (unresolved)"
+    );
+    assert_eq!(fix("jit 0x1234"), "unresolved 0x1234");
+
+    // The built-in frame pattern still works, and uses the templates from
+    // `[template]`.
+    assert_eq!(
+        fix("#00: ???[tests/example-linux +0x1146]"),
+        "#00: main (/home/njn/moz/fix-stacks/tests/example.c:26)"
+    );
+    assert_eq!(
+        fix("#00: ???[tests/pe-exports.dll +0x1000]"),
+        "exported_A in tests/pe-exports.dll"
+    );
+
+    // Version control schemes are added to the built-in ones.
+    let config = Config::parse("[vcs]\nschemes = ['svn', 'gcs:none']").unwrap();
+    let mut schemes = VcsScheme::builtin();
+    schemes.extend(config.vcs_schemes);
+    assert_eq!(
+        VcsPath::parse("gcs:bucket:a.cpp:", &schemes).map(|p| p.path),
+        Some("a.cpp")
+    );
+    assert_eq!(
+        schemes.last(),
+        Some(&VcsScheme::from_spec("gcs:none").unwrap())
+    );
+
+    // Bad config files.
+    let err = |text| format!("{:#}", Config::parse(text).err().unwrap());
+    assert_eq!(
+        err("[[frame]]\npattern = '(?P<module>.*) (?P<bad>.*)'"),
+        "parse frame pattern `(?P<module>.*) (?P<bad>.*)` (unknown capture `bad`) in"
+    );
+    assert!(err("[[frame]]\npattern = '(?P<module>.*)'").contains("it needs"));
+    assert_eq!(
+        err("[[frame]]\npattern = '(?P<address>.*)'\ntemplate = '{nope}'"),
+        "parse frame template in: unknown field `{nope}` in template `{nope}`"
+    );
+    assert_eq!(
+        err("[[frame]]\npattern = '(?P<address>.*)'\ntemplate_fxed = ''"),
+        "parse frame template in: unknown resolution level `fxed`"
+    );
+    assert_eq!(
+        err("[template]\nfixed = '{function'"),
+        "parse template in: unclosed `{` in template `{function`"
+    );
+    assert_eq!(err("[[frame]]\ntemplate = ''"), "find `frame.pattern` in");
+    assert_eq!(err("[frames]"), "parse (unknown key `frames`)");
+    assert_eq!(
+        err("[vcs]\nschemes = ['svn:sometimes']"),
+        "parse VCS scheme in: bad revision kind `sometimes`"
+    );
+    assert_eq!(
+        err("[vcs]\nscheme = []"),
+        "parse (unknown key `vcs.scheme`)"
+    );
+}
+
+#[test]
+fn test_templates() {
+    let mut fixer = Fixer::new(JsonMode::Yes, None, None);
+    fixer
+        .templates
+        .set(
+            "fixed",
+            "{prefix}{frameno}|{function}|{short_function}|{file}|{basename}|{line}|{column}|\
+             {module}|{offset}|{function_offset}|{source}{suffix}",
+        )
+        .unwrap();
+    fixer
+        .templates
+        .set("unresolved", "{{{frameno}}} {function} {location}")
+        .unwrap();
+    let mut fix = |line: &str| fixer.fix(line.to_string());
+
+    assert_eq!(
+        fix("\"#05: ???[tests/example-linux +0x1146]\""),
+        "\"#05: 05|main|main|/home/njn/moz/fix-stacks/tests/example.c|example.c|26||\
+         tests/example-linux|0x1146|0x16|native\""
+    );
+    assert_eq!(
+        fix("#07: foo[tests/example-linux +0x0]"),
+        "{07} foo tests/example-linux + 0x0"
+    );
+
+    // There is no template for frames with only a function name.
+    assert_eq!(
+        fix("#00: ???[tests/pe-exports.dll +0x1000]"),
+        "#00: pe-exports!exported_A + 0x0"
+    );
+}
+
+#[test]
+fn test_demangle() {
+    use demangle::{format_name, Style};
+
+    let name = |mangled, style| format_name(mangled, style, false);
+
+    // A mangled C++ name.
+    let mangled = "_ZN7mozilla5MaybeI8nsTArrayIiEE7emplaceEPKc";
+    assert_eq!(
+        name(mangled, Style::Full),
+        "mozilla::Maybe<nsTArray<int> >::emplace(char const*)"
+    );
+    assert_eq!(
+        name(mangled, Style::NameOnly),
+        "mozilla::Maybe<nsTArray<int> >::emplace"
+    );
+    assert_eq!(
+        name(mangled, Style::Collapsed),
+        "mozilla::Maybe<...>::emplace"
+    );
+    assert_eq!(
+        name(mangled, Style::Mangled),
+        format!(
+            "mozilla::Maybe<nsTArray<int> >::emplace(char const*) [{}]",
+            mangled
+        )
+    );
+
+    // An already demangled name, as in Breakpad symbol files.
+    let demangled = "mozilla::dom::Foo<int>::operator<<(Bar<char>&) const";
+    assert_eq!(name(demangled, Style::Full), demangled);
+    assert_eq!(
+        name(demangled, Style::NameOnly),
+        "mozilla::dom::Foo<int>::operator<<"
+    );
+    assert_eq!(
+        name(demangled, Style::Collapsed),
+        "mozilla::dom::Foo<...>::operator<<"
+    );
+    assert_eq!(name(demangled, Style::Mangled), demangled);
+    assert_eq!(name("main", Style::Mangled), "main");
+
+    // Rust names, which only have a hash if they were already demangled.
+    let rust = "core::ptr::drop_in_place<alloc::string::String>::h0123456789abcdef";
+    assert_eq!(name(rust, Style::Full), rust);
+    assert_eq!(
+        format_name(rust, Style::Full, true),
+        "core::ptr::drop_in_place<alloc::string::String>"
+    );
+    assert_eq!(
+        format_name(rust, Style::Collapsed, true),
+        "core::ptr::drop_in_place<...>"
+    );
+    assert_eq!(
+        name("_ZN4core9panicking5panic17h0123456789abcdefE", Style::Full),
+        "core::panicking::panic"
+    );
+    assert_eq!(
+        demangle::strip_rust_hash("foo::hash::hello"),
+        "foo::hash::hello"
+    );
+
+    // Edge cases for removing parameters and templates.
+    assert_eq!(
+        demangle::strip_parameters("(anonymous namespace)::Foo::operator()(int)"),
+        "(anonymous namespace)::Foo::operator()"
+    );
+    assert_eq!(
+        demangle::strip_parameters("(anonymous namespace)"),
+        "(anonymous namespace)"
+    );
+    assert_eq!(
+        demangle::collapse_templates("Foo<Bar<int>, std::function<void ()>>::operator->"),
+        "Foo<...>::operator->"
+    );
+    assert_eq!(demangle::collapse_templates("operator<=>"), "operator<=>");
+    assert_eq!(
+        demangle::collapse_templates("my_operator<int>::operators<int>"),
+        "my_operator<...>::operators<...>"
+    );
+
+    // `operator()` is kept whether the name was mangled or not, e.g. for
+    // lambdas.
+    assert_eq!(name("_ZN3FooclEv", Style::Full), "Foo::operator()()");
+    assert_eq!(name("_ZN3FooclEv", Style::NameOnly), "Foo::operator()");
+    assert_eq!(name("_ZN3FooclEv", Style::Collapsed), "Foo::operator()");
+    assert_eq!(
+        name("_ZZ4mainENKUlvE_clEv", Style::NameOnly),
+        "main::{lambda()#1}::operator()"
+    );
+    assert_eq!(
+        name("Foo::operator()(int)", Style::NameOnly),
+        "Foo::operator()"
+    );
+    assert_eq!(name("Foo::operator()", Style::NameOnly), "Foo::operator()");
+    assert_eq!(signature::normalize("_ZN3FooclEv"), "Foo::operator()");
+
+    // The options belong to each `Fixer`.
+    let options = NameOptions {
+        style: Style::NameOnly,
+        strip_rust_hash: true,
+    };
+    assert_eq!(
+        options.display_name(rust),
+        "core::ptr::drop_in_place<alloc::string::String>"
+    );
+    assert_eq!(
+        options.short_name(mangled),
+        "mozilla::Maybe<nsTArray<int> >::emplace"
+    );
+
+    // Names that aren't mangled are printed as usual in `mangled` style.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.name_options.style = Style::Mangled;
+    assert_eq!(
+        fixer.fix("#00: ???[tests/example-linux +0x1146]".to_string()),
+        "#00: main (/home/njn/moz/fix-stacks/tests/example.c:26)"
+    );
+
+    assert!(demangle::Style::parse("name-only").is_ok());
+    assert!(demangle::Style::parse("short").is_err());
+}
+
+#[test]
+fn test_signature() {
+    let fix = |fixer: &mut Fixer, input: &str| {
+        let mut output = vec![];
+        fix_stream(fixer, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    // Frames are normalized whether they are resolved or not, and each stack
+    // gets a signature, with irrelevant frames skipped.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.signature = Some(SignatureInfo::default());
+    let input = "\
+        Crash!\n\
+        #00: abort[libc.so.6 +0x1234]\n\
+        #01: moz_xmalloc[libmozalloc.so +0x10]\n\
+        #02: nsTArray_base<nsTArrayInfallibleAllocator, nsTArray_RelocateUsingMemutils>::EnsureCapacity(unsigned long, unsigned long)[libxul.so +0x20]\n\
+        #03: ???[tests/example-linux +0x11b0]\n\
+        #04: ???[tests/example-linux +0x1160]\n\
+        Another crash!\n\
+        #00: ???[tests/nonexistent.so +0x10]\n\
+        #01: ???[tests/example-linux +0x1130]\n";
+    assert_eq!(
+        fix(&mut fixer, input),
+        "\
+        Crash!\n\
+        #00: abort (libc.so.6 + 0x1234)\n\
+        #01: moz_xmalloc (libmozalloc.so + 0x10)\n\
+        #02: nsTArray_base<nsTArrayInfallibleAllocator, nsTArray_RelocateUsingMemutils>::EnsureCapacity(unsigned long, unsigned long) (libxul.so + 0x20)\n\
+        #03: g (/home/njn/moz/fix-stacks/tests/example.c:11)\n\
+        #04: f (/home/njn/moz/fix-stacks/tests/example.c:16)\n\
+        Signature: [@ moz_xmalloc | nsTArray_base<...>::EnsureCapacity | g]\n\
+        Another crash!\n\
+        #00: ??? (tests/nonexistent.so + 0x10)\n\
+        #01: main (/home/njn/moz/fix-stacks/tests/example.c:24)\n\
+        Signature: [@ nonexistent.so@0x10]\n"
+    );
+
+    // The lists can be changed by the config file.
+    let config = Config::parse(
+        r#"
+        [signature]
+        irrelevant = ['nonexistent\.so@.*', 'g']
+        prefix = ['f']
+        "#,
+    )
+    .unwrap();
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.signature = Some(config.signature);
+    let input = "\
+        #00: ???[tests/nonexistent.so +0x10]\n\
+        #01: ???[tests/example-linux +0x11b0]\n\
+        #02: ???[tests/example-linux +0x1160]\n\
+        #03: ???[tests/example-linux +0x1130]";
+    assert!(fix(&mut fixer, input).ends_with("example.c:24)\nSignature: [@ f | main]\n"));
+
+    // If every frame is irrelevant, the first is used.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.signature = Some(SignatureInfo::default());
+    assert!(fix(
+        &mut fixer,
+        "#00: abort[libc.so.6 +0x1]\n#01: raise[libc.so.6 +0x2]\n"
+    )
+    .ends_with("\nSignature: [@ abort]\n"));
+
+    // Malformed frames are still part of the stack.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.signature = Some(SignatureInfo::default());
+    assert!(fix(
+        &mut fixer,
+        "#00: abort[libc.so.6 +0x1]\n#01: ???[lib/libxul.so +0x1000000000000000A]\n"
+    )
+    .ends_with("\nSignature: [@ libxul.so@0x1000000000000000a]\n"));
+    assert!(fix(
+        &mut fixer,
+        "#00: Foo::Bar(int)[libxul.so +0x10000000000000000]\n"
+    )
+    .ends_with("\nSignature: [@ Foo::Bar]\n"));
+
+    let err = |text| Config::parse(text).err().unwrap().to_string();
+    assert_eq!(
+        err("[signature]\nirrelevant = 'abort'"),
+        "read `signature.irrelevant` in"
+    );
+    assert_eq!(
+        err("[signature]\nprefix = ['(']"),
+        "parse signature pattern in"
+    );
+    assert_eq!(
+        err("[signature]\nskip = []"),
+        "parse (unknown key `signature.skip`)"
+    );
+
+    assert_eq!(signature::normalize("foo::bar(int) + 0x12"), "foo::bar");
+    assert_eq!(signature::unresolved(None, "1234"), "@0x1234");
+    assert_eq!(
+        signature::unresolved(Some("/system/lib64/libc.so"), "00000000001A2B"),
+        "libc.so@0x1a2b"
+    );
+}
+
 #[test]
 fn test_regex() {
     let mut fixer = Fixer::new(JsonMode::No, None, None);
@@ -537,7 +1554,8 @@ fn test_localpath() {
         JsonMode::No,
         None,
         Some(LocalFileInfo {
-            local_dir: "tests".to_string(),
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
         }),
     );
 
@@ -569,3 +1587,1153 @@ fn test_localpath() {
     func("g", 0x11bc, 12);
     func("g", 0x11de, 14);
 }
+
+#[test]
+fn test_android_apk() {
+    // `example-android.apk` contains `example-linux` as a stored entry, whose
+    // data starts at offset 0x36 within the APK.
+    let check = |fixer: &mut Fixer, in_file_name: &str, addr, expected: &str| {
+        let line = format!("#00: ???[{} +0x{:x}]", in_file_name, addr);
+        assert_eq!(fixer.fix(line), expected);
+    };
+    let lib = "lib/x86_64/example-linux";
+    let main24 = "#00: main (/home/njn/moz/fix-stacks/tests/example.c:24)";
+
+    // The library's IDs can be read from its headers within the APK.
+    let data = fs::read("tests/example-linux").unwrap();
+    let object = Object::parse(&data).unwrap();
+    assert_eq!(
+        apk::read_entry_ids("tests/example-android.apk", lib),
+        Some((object.code_id().unwrap().to_string(), object.debug_id()))
+    );
+    assert_eq!(
+        apk::read_entry_ids("tests/example-android.apk", "lib/nope.so"),
+        None
+    );
+
+    // The library is read from the APK, given either its path within the APK
+    // or an offset into the APK.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    let apk_lib = format!("tests/example-android.apk!/{}", lib);
+    check(&mut fixer, &apk_lib, 0x1130, main24);
+    check(&mut fixer, "tests/example-android.apk", 0x1166, main24);
+    check(
+        &mut fixer,
+        "tests/example-android.apk",
+        0x10,
+        "#00: ??? (tests/example-android.apk + 0x10)",
+    );
+    check(
+        &mut fixer,
+        "tests/example-android.apk!/lib/nope.so",
+        0x1130,
+        "#00: ??? (tests/example-android.apk!/lib/nope.so + 0x1130)",
+    );
+    assert!(fixer.file_infos.contains_key(&apk_lib));
+
+    // The offset into the library is converted to an address, which differs
+    // for a library linked by `lld`. The data of `libexample-lld.so` starts
+    // at offset 0x3a within `example-android-lld.apk`, and `main` is at
+    // offset 0x41a and address 0x141a within it.
+    check(
+        &mut fixer,
+        "tests/example-android-lld.apk",
+        0x3a + 0x41a,
+        "#00: main (tests/example.c:24)",
+    );
+
+    // With `--local`, the APK is found by its file name, and the copy of the
+    // library in the local directory is used because its build ID matches.
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        None,
+        Some(LocalFileInfo {
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
+        }),
+    );
+    let device_apk = "/data/app/org.mozilla.example/example-android.apk";
+    check(
+        &mut fixer,
+        &format!("{}!/{}", device_apk, lib),
+        0x1130,
+        main24,
+    );
+    check(&mut fixer, device_apk, 0x1166, main24);
+    assert!(fixer.file_infos.contains_key("tests/example-linux"));
+    assert_eq!(fixer.file_infos.len(), 1);
+}
+
+#[test]
+fn test_android_tombstone() {
+    let build_id = "6c974ebe5232ee469d6b7847a670b2a956f8aede";
+    let path = "/data/app/org.mozilla.example/lib/x86_64/example-linux";
+    let location = "(/home/njn/moz/fix-stacks/tests/example.c";
+
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        None,
+        Some(LocalFileInfo {
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
+        }),
+    );
+    let mut fix = |line: String| fixer.fix(line);
+
+    // The library is found in the local directory, and its build ID matches.
+    assert_eq!(
+        fix(format!(
+            "    #00 pc 0000000000001130  {} (BuildId: {})",
+            path, build_id
+        )),
+        format!(
+            "    #00 pc 0000000000001130  {} (main) {}:24) (BuildId: {})",
+            path, location, build_id
+        )
+    );
+
+    // The function annotation is replaced, and the offset annotation kept.
+    assert_eq!(
+        fix(format!(
+            "#01 pc 00000000000011bc  {} (offset 0x7000) (g(int)+12) (BuildId: {})",
+            path, build_id
+        )),
+        format!(
+            "#01 pc 00000000000011bc  {} (offset 0x7000) (g) {}:12) (BuildId: {})",
+            path, location, build_id
+        )
+    );
+
+    // Without a build ID, the library is found by name.
+    assert_eq!(
+        fix(format!("#02 pc 0000000000001160  {}", path)),
+        format!("#02 pc 0000000000001160  {} (f) {}:16)", path, location)
+    );
+
+    // Paths can contain spaces, and text after the annotations is kept, once.
+    let spaced_path = "/data/app/org.mozilla example/lib/x86_64/example-linux";
+    assert_eq!(
+        fix(format!("#02 pc 0000000000001160  {}", spaced_path)),
+        format!(
+            "#02 pc 0000000000001160  {} (f) {}:16)",
+            spaced_path, location
+        )
+    );
+    assert_eq!(
+        fix(format!(
+            "#02 pc 0000000000001160  {} (f()+4) (BuildId: {}) [extra]",
+            spaced_path, build_id
+        )),
+        format!(
+            "#02 pc 0000000000001160  {} (f) {}:16) (BuildId: {}) [extra]",
+            spaced_path, location, build_id
+        )
+    );
+    assert_eq!(
+        fix(format!("#02 pc 0000000000001160  {} (f()+4) [extra]", path)),
+        format!(
+            "#02 pc 0000000000001160  {} (f) {}:16) [extra]",
+            path, location
+        )
+    );
+
+    // If the build ID doesn't match, the frame is left alone.
+    let line = format!("#03 pc 0000000000001130  {} (BuildId: 1234abcd)", path);
+    assert_eq!(fix(line.clone()), line);
+
+    // Unknown libraries are left alone.
+    let line = "#04 pc 0000000000001130  /system/lib64/libc.so (abort+4)".to_string();
+    assert_eq!(fix(line.clone()), line);
+
+    // A library loaded directly from an APK is named by the APK, with the
+    // offset of its mapping within the APK. The data of `example-linux`
+    // starts at offset 0x36 within `example-android.apk`. The APK is found in
+    // the local directory, and so is a copy of the library.
+    let device_apk = "/data/app/org.mozilla.example/example-android.apk";
+    assert_eq!(
+        fix(format!(
+            "#05 pc 00000000000011bc  {} (offset 0x36) (BuildId: {})",
+            device_apk, build_id
+        )),
+        format!(
+            "#05 pc 00000000000011bc  {} (offset 0x36) (g) {}:12) (BuildId: {})",
+            device_apk, location, build_id
+        )
+    );
+
+    // Without a local copy, the library is read from the APK, once its build
+    // ID has been checked.
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    let mut fix = |line: String| fixer.fix(line);
+    let apk = "tests/example-android.apk";
+    assert_eq!(
+        fix(format!(
+            "#06 pc 00000000000011bc  {} (offset 0x36) (BuildId: {})",
+            apk, build_id
+        )),
+        format!(
+            "#06 pc 00000000000011bc  {} (offset 0x36) (g) {}:12) (BuildId: {})",
+            apk, location, build_id
+        )
+    );
+    let line = format!(
+        "#07 pc 00000000000011bc  {} (offset 0x36) (BuildId: 1234abcd)",
+        apk
+    );
+    assert_eq!(fix(line.clone()), line);
+    let line = format!("#08 pc 00000000000011bc  {} (offset 0x10)", apk);
+    assert_eq!(fix(line.clone()), line);
+
+    // The address is already relative to the library, even if its file
+    // offsets differ from its addresses.
+    let lld_apk = "tests/example-android-lld.apk";
+    assert_eq!(
+        fix(format!(
+            "#09 pc 000000000000141a  {} (offset 0x3a)",
+            lld_apk
+        )),
+        format!(
+            "#09 pc 000000000000141a  {} (offset 0x3a) (main) (tests/example.c:24)",
+            lld_apk
+        )
+    );
+
+    // In JSON mode, new strings are escaped.
+    let mut fixer = Fixer::new(JsonMode::Yes, None, None);
+    fixer.path_map.add("/home/njn/moz=c:\\src").unwrap();
+    assert_eq!(
+        fixer.fix(format!("#09 pc 00000000000011bc  {} (offset 0x36)", apk)),
+        format!(
+            "#09 pc 00000000000011bc  {} (offset 0x36) (g) (c:\\\\src/fix-stacks/tests/example.c:12)",
+            apk
+        )
+    );
+
+    // A library can also be found in a build ID directory.
+    let build_id_dir = TempDir::new("build-id");
+    let lib_dir = build_id_dir.join(&build_id[..2]);
+    fs::create_dir_all(&lib_dir).unwrap();
+    fs::copy(
+        "tests/example-linux",
+        lib_dir.join(format!("{}.debug", &build_id[2..])),
+    )
+    .unwrap();
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.build_id_dirs.push(build_id_dir.to_path_buf());
+    let line = fixer.fix(format!(
+        "#10 pc 00000000000011bc  libxul.so (BuildId: {})",
+        build_id
+    ));
+    assert_eq!(
+        line,
+        format!(
+            "#10 pc 00000000000011bc  libxul.so (g) {}:12) (BuildId: {})",
+            location, build_id
+        )
+    );
+}
+
+#[test]
+fn test_local_build_ids() {
+    // The IDs read from the headers match those from parsing the whole file.
+    for bin_file in ["tests/example-linux", "tests/example-linux-inline"] {
+        let data = fs::read(bin_file).unwrap();
+        let object = Object::parse(&data).unwrap();
+        let mut file = fs::File::open(bin_file).unwrap();
+        assert_eq!(
+            local::read_elf_ids(&mut file, 0),
+            Some((object.code_id().unwrap().to_string(), object.debug_id()))
+        );
+    }
+    // Files without a build ID note, and other formats, must be parsed fully.
+    for bin_file in ["tests/example-linux-fallback", "tests/example-windows.exe"] {
+        let mut file = fs::File::open(bin_file).unwrap();
+        assert_eq!(local::read_elf_ids(&mut file, 0), None);
+    }
+
+    // Two different binaries with the same file name, in subdirectories.
+    let local_dir = TempDir::new("local");
+    for (dir, bin_file) in [("a", "example-linux-fallback"), ("b", "example-linux")] {
+        fs::create_dir_all(local_dir.join(dir)).unwrap();
+        fs::copy(
+            Path::new("tests").join(bin_file),
+            local_dir.join(dir).join("example-linux"),
+        )
+        .unwrap();
+    }
+    let fixer = |recursive| {
+        Fixer::new(
+            JsonMode::No,
+            None,
+            Some(LocalFileInfo {
+                local_dirs: vec![PathBuf::from("tests/bpsyms"), local_dir.to_path_buf()],
+                recursive,
+            }),
+        )
+    };
+    let line = "#00: ???[/device/example-linux +0x1130]";
+    let main24 = "#00: main (/home/njn/moz/fix-stacks/tests/example.c:24)";
+
+    // Subdirectories are only searched when requested.
+    assert_eq!(
+        fixer(false).fix(line.to_string()),
+        "#00: ??? (/device/example-linux + 0x1130)"
+    );
+
+    // Without a build ID, the first file with the right name is used, which
+    // is the wrong one.
+    assert_ne!(fixer(true).fix(line.to_string()), main24);
+
+    // With a debug ID from a module list, the right one is used.
+    let data = fs::read("tests/example-linux").unwrap();
+    let debug_id = Object::parse(&data).unwrap().debug_id();
+    let mut fixer2 = fixer(true);
+    let module = format!(
+        "Module|example-linux||example-linux|{}|0x0|0x1000|1",
+        debug_id.breakpad()
+    );
+    assert_eq!(fixer2.fix(module.clone()), module);
+    assert_eq!(fixer2.fix(line.to_string()), main24);
+    assert_eq!(fixer2.stats.mismatches, 1);
+
+    // With a build ID from a tombstone, the right one is used, even if the
+    // file name differs.
+    assert_eq!(
+        fixer(true).fix(
+            "#00 pc 0000000000001130  /device/libexample.so \
+             (BuildId: 6c974ebe5232ee469d6b7847a670b2a956f8aede)"
+                .to_string()
+        ),
+        "#00 pc 0000000000001130  /device/libexample.so (main) \
+         (/home/njn/moz/fix-stacks/tests/example.c:24) \
+         (BuildId: 6c974ebe5232ee469d6b7847a670b2a956f8aede)"
+    );
+}
+
+#[test]
+fn test_jit() {
+    let dir = TempDir::new("jit");
+
+    // A perf map file.
+    let perf_map = dir.join("perf-123.map");
+    fs::write(
+        &perf_map,
+        "7f0000001000 100 Baseline: foo.js:10:5\n7f0000001100 80 Ion: bar.js:20:1\n",
+    )
+    .unwrap();
+
+    // A jitdump file with a function with debug info, which replaces the
+    // first function in the perf map.
+    let mut jitdump = vec![];
+    let u32 = |v: u32, out: &mut Vec<u8>| out.extend_from_slice(&v.to_le_bytes());
+    let u64 = |v: u64, out: &mut Vec<u8>| out.extend_from_slice(&v.to_le_bytes());
+    u32(0x4a695444, &mut jitdump); // magic
+    u32(1, &mut jitdump); // version
+    u32(40, &mut jitdump); // header size
+    jitdump.extend_from_slice(&[0; 28]); // the rest of the header
+
+    let mut debug_info = vec![];
+    u64(0x7f0000001000, &mut debug_info); // code address
+    u64(2, &mut debug_info); // number of entries
+    for (address, line, column, file) in [
+        (0x7f0000001000, 30, 5, &b"baz.js\0"[..]),
+        (0x7f0000001040, 31, 0, b"\xff\0"),
+    ] {
+        u64(address, &mut debug_info);
+        u32(line, &mut debug_info);
+        u32(column, &mut debug_info); // discriminator, i.e. column
+        debug_info.extend_from_slice(file);
+    }
+    let mut code_load = vec![];
+    u32(123, &mut code_load); // pid
+    u32(123, &mut code_load); // tid
+    u64(0x7f0000001000, &mut code_load); // vma
+    u64(0x7f0000001000, &mut code_load); // code address
+    u64(0x100, &mut code_load); // code size
+    u64(0, &mut code_load); // code index
+    code_load.extend_from_slice(b"Ion: baz.js:30:1\0");
+    for (id, record) in [(2, debug_info), (0, code_load)] {
+        u32(id, &mut jitdump);
+        u32(16 + record.len() as u32, &mut jitdump);
+        u64(0, &mut jitdump); // timestamp
+        jitdump.extend_from_slice(&record);
+    }
+    let jitdump_file = dir.join("jit-123.dump");
+    fs::write(&jitdump_file, jitdump).unwrap();
+
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    let jit_files = vec![
+        perf_map.to_str().unwrap().to_string(),
+        jitdump_file.to_str().unwrap().to_string(),
+    ];
+    fixer.jit_info = JitInfo {
+        file_info: Fixer::build_file_info_jit(&jit_files, Level::Normal),
+        jit_files: jit_files.clone(),
+    };
+
+    let mut func = |in_file_name: &str, addr: u64, expected: &str| {
+        let line = format!("#00: ???[{} +0x{:x}]", in_file_name, addr);
+        assert_eq!(fixer.fix(line), expected);
+    };
+    func("???", 0x7f0000001010, "#00: Ion: baz.js:30:1 (baz.js:30)");
+    func("???", 0x7f0000001050, "#00: Ion: baz.js:30:1 (baz.js:31)");
+    func(
+        &jit_files[0],
+        0x7f0000001120,
+        &format!("#00: Ion: bar.js:20:1 ({} + 0x7f0000001120)", jit_files[0]),
+    );
+    func("???", 0x7f0000002000, "#00: ??? (??? + 0x7f0000002000)");
+
+    // The column is used in templates if it is known.
+    fixer
+        .templates
+        .set(
+            "fixed",
+            "{prefix}{function} ({basename}:{line}:{column}){suffix}",
+        )
+        .unwrap();
+    let mut fix = |line: &str| fixer.fix(line.to_string());
+    assert_eq!(
+        fix("#00: ???[??? +0x7f0000001010]"),
+        "#00: Ion: baz.js:30:1 (baz.js:30:5)"
+    );
+    assert_eq!(
+        fix("#00: ???[??? +0x7f0000001050]"),
+        "#00: Ion: baz.js:30:1 (baz.js:31:)"
+    );
+}
+
+#[test]
+fn test_perf_script() {
+    // `example-linux-inline` is a non-PIE executable, so the addresses in its
+    // debug info differ from its file offsets. `main` is at file offset
+    // 0x1040, with `f` inlined into it at 0x1041..0x105c, `g` inlined into
+    // `f` at 0x1041..0x1052, and `g` inlined into `f` again at
+    // 0x1052..0x105c.
+    let dir = TempDir::new("perf");
+    let maps_file = dir.join("maps");
+    fs::write(
+        &maps_file,
+        "7f1000000000-7f1000001000 r--p 00000000 fd:01 123  tests/example-linux-inline\n\
+         7f1000001000-7f1000002000 r-xp 00001000 fd:01 123  tests/example-linux-inline\n\
+         7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0    [stack]\n\
+         7f3000000000-7f3000001000 rw-p 00000000 00:00 0\n\
+         7f2000000000-7f2000001000 r-xp 00002000 fd:01 456  /opt/My App/libfoo.so\n",
+    )
+    .unwrap();
+    let mut maps = perf::read_maps_file(maps_file.to_str().unwrap()).unwrap();
+    assert_eq!(maps.len(), 3);
+    let mapping = maps.pop().unwrap();
+    assert_eq!(mapping.path, "/opt/My App/libfoo.so");
+    assert_eq!(mapping.file_offset, 0x2000);
+
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    fixer.perf_info = Some(PerfInfo::new(maps));
+    fixer.keep_inlinees = true;
+
+    let mut fix = |line: &str| fixer.fix(line.to_string());
+
+    // A mmap event for pid 100, which is used instead of the maps file.
+    let mmap = "example 100 1000.000000: PERF_RECORD_MMAP2 100/100: \
+                [0x7f0000001000(0x1000) @ 0x1000 fd:01 123 0]: r-xp tests/example-linux-inline";
+    assert_eq!(fix(mmap), mmap);
+
+    let sample = "example   100/100  1000.000001:     250000 cpu-clock:";
+    assert_eq!(fix(sample), sample);
+    assert_eq!(
+        fix("\t    7f0000001048 [unknown] (tests/example-linux-inline)"),
+        "\t    7f0000001048 g (tests/example-linux-inline)\n\
+         \t    7f0000001048 f (tests/example-linux-inline)\n\
+         \t    7f0000001048 main (tests/example-linux-inline)"
+    );
+    assert_eq!(
+        fix("\t    7f0000001055 main+0x15 (tests/example-linux-inline)"),
+        "\t    7f0000001055 g (tests/example-linux-inline)\n\
+         \t    7f0000001055 f (tests/example-linux-inline)\n\
+         \t    7f0000001055 main (tests/example-linux-inline)"
+    );
+    assert_eq!(
+        fix("\t    7f000000105c main+0x1c (tests/example-linux-inline)"),
+        "\t    7f000000105c main (tests/example-linux-inline)"
+    );
+
+    // Frames that can't be resolved are unchanged.
+    let unchanged = [
+        "\t    7f2000001048 [unknown] (tests/example-linux-inline)",
+        "\t    7f0000003000 [unknown] (tests/example-linux-inline)",
+        "\t    ffffffff81000000 do_syscall_64+0x5c ([kernel.kallsyms])",
+        "\t    7f2000000000 [unknown] ([unknown])",
+    ];
+    for line in unchanged {
+        assert_eq!(fix(line), line);
+    }
+
+    // Another pid has no mmap events, so the maps file is used.
+    fix("example   200/200  1000.000002:     250000 cpu-clock:");
+    assert_eq!(
+        fix("\t    7f100000105c main+0x1c (tests/example-linux-inline)"),
+        "\t    7f100000105c main (tests/example-linux-inline)"
+    );
+    assert_eq!(
+        fix("\t    7f0000001048 [unknown] (tests/example-linux-inline)"),
+        "\t    7f0000001048 [unknown] (tests/example-linux-inline)"
+    );
+
+    // A library within an APK is read in place. It was linked by `lld`, so
+    // its file offsets differ from its addresses: `main` is at offset 0x41a
+    // and address 0x141a.
+    let lib = "tests/example-android-lld.apk!/lib/x86_64/libexample-lld.so";
+    fix(&format!(
+        "example 300 1000.000003: PERF_RECORD_MMAP2 300/300: \
+         [0x7f4000000000(0x1000) @ 0x0 fd:01 789 0]: r-xp {}",
+        lib
+    ));
+    fix("example   300/300  1000.000004:     250000 cpu-clock:");
+    assert_eq!(
+        fix(&format!("\t    7f400000041a [unknown] ({})", lib)),
+        format!("\t    7f400000041a main ({})", lib)
+    );
+}
+
+#[test]
+fn test_symbolication_server() {
+    let inline_debug_id = {
+        let data = fs::read("tests/example-linux-inline").unwrap();
+        let debug_id = Object::parse(&data).unwrap().debug_id();
+        debug_id.breakpad().to_string()
+    };
+
+    // Modules are found in the Breakpad symbols directory, or in the local
+    // directories by debug ID.
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        Some(BreakpadInfo {
+            syms_dir: "tests/bpsyms".to_string(),
+        }),
+        Some(LocalFileInfo {
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
+        }),
+    );
+    fixer.keep_inlinees = true;
+
+    let request = json!({
+        "jobs": [{
+            "memoryMap": [
+                ["example-linux", "BE4E976C325246EE9D6B7847A670B2A90"],
+                ["example-linux-inline", inline_debug_id],
+                ["example-linux", "00000000000000000000000000000000F"],
+                ["../example-linux", "BE4E976C325246EE9D6B7847A670B2A90"],
+            ],
+            "stacks": [[[0, 0x1146], [1, 0x1048], [1, 0x105c], [2, 0x1146], [3, 0x1146], [-1, 0x1234]]],
+        }]
+    });
+    let file = "hg:hg.mozilla.org/integration/autoland:\
+                /home/njn/moz/fix-stacks/tests/example.c:94d31f914f29e72dd81a8880100d12f67e48a5b0";
+    let inline_file = "tests/example.c";
+    let sym_file = "tests/bpsyms/example-linux/BE4E976C325246EE9D6B7847A670B2A90/example-linux.sym";
+    let expected = json!({
+        "results": [{
+            "stacks": [[
+                {
+                    "frame": 0,
+                    "module": "example-linux",
+                    "module_offset": "0x1146",
+                    "function": "main",
+                    "function_offset": "0x16",
+                    "file": file,
+                    "line": 26,
+                    "symbol_source": { "kind": "breakpad", "path": sym_file },
+                },
+                {
+                    "frame": 1,
+                    "module": "example-linux-inline",
+                    "module_offset": "0x1048",
+                    "function": "main",
+                    "function_offset": "0x8",
+                    "file": inline_file,
+                    "line": 26,
+                    "symbol_source": { "kind": "native" },
+                    "inlines": [
+                        { "function": "g", "file": inline_file, "line": 12 },
+                        { "function": "f", "file": inline_file, "line": 18 },
+                    ],
+                },
+                {
+                    "frame": 2,
+                    "module": "example-linux-inline",
+                    "module_offset": "0x105c",
+                    "function": "main",
+                    "function_offset": "0x1c",
+                    "file": inline_file,
+                    "line": 28,
+                    "symbol_source": { "kind": "native" },
+                },
+                { "frame": 3, "module": "example-linux", "module_offset": "0x1146" },
+                { "frame": 4, "module": "../example-linux", "module_offset": "0x1146" },
+                { "frame": 5, "module_offset": "0x1234" },
+            ]],
+            "found_modules": {
+                "example-linux/BE4E976C325246EE9D6B7847A670B2A90": true,
+                format!("example-linux-inline/{}", inline_debug_id): true,
+                "example-linux/00000000000000000000000000000000F": false,
+                "../example-linux/BE4E976C325246EE9D6B7847A670B2A90": false,
+            },
+        }]
+    });
+    assert_eq!(fixer.symbolicate_v5(&request).unwrap(), expected);
+    let stats = &fixer.stats;
+    assert_eq!(
+        (stats.frames, stats.fixed, stats.name_only, stats.unresolved),
+        (6, 3, 0, 3)
+    );
+
+    // A single job is also accepted.
+    let job = json!({
+        "memoryMap": [["example-linux", "BE4E976C325246EE9D6B7847A670B2A90"]],
+        "stacks": [[[0, 0x1160]]],
+    });
+    let result = fixer.symbolicate_v5(&job).unwrap();
+    assert_eq!(result["results"][0]["stacks"][0][0]["function"], "f");
+
+    // Requests over HTTP. Browsers can only use the server from the allowed
+    // origins.
+    let mut send = |raw: String| {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            let mut response = String::new();
+            io::Read::read_to_string(&mut stream, &mut response).unwrap();
+            response
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let allowed_origins = vec![server::DEFAULT_ORIGIN.to_string()];
+        server::handle_connection(stream, &allowed_origins, &mut |request| {
+            fixer.handle_request(request)
+        })
+        .unwrap();
+        client.join().unwrap()
+    };
+    let mut request = |origin: Option<&str>, content_length: usize, body: &str| {
+        let origin = origin.map_or(String::new(), |origin| format!("Origin: {}\r\n", origin));
+        send(format!(
+            "POST /symbolicate/v5 HTTP/1.1\r\nContent-Type: application/json\r\n\
+             {}Content-Length: {}\r\n\r\n{}",
+            origin, content_length, body
+        ))
+    };
+    let body = job.to_string();
+
+    let response = request(None, body.len(), &body);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(!response.contains("Access-Control-Allow-Origin"));
+    let (_, response_body) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(response_body).unwrap(),
+        result
+    );
+
+    let response = request(Some("https://profiler.firefox.com"), body.len(), &body);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Access-Control-Allow-Origin: https://profiler.firefox.com\r\n"));
+
+    let response = request(Some("https://example.com"), body.len(), &body);
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(!response.contains("Access-Control-Allow-Origin"));
+
+    // Bodies that are too large or too short are rejected.
+    let response = request(None, 1 << 40, &body);
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.contains("failed to accept request body of 1099511627776 bytes"));
+    let response = request(None, body.len() + 1, &body);
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.contains("failed to read request body (it ended early)"));
+
+    // So are heads with a line over 8 KiB, or over 64 KiB in all. The requests
+    // end where the server stops reading, so it doesn't reset the connection.
+    let request_line = "POST /symbolicate/v5 HTTP/1.1\r\n";
+    let response = send(format!("{}X: {}", request_line, "a".repeat(8 * 1024 - 3)));
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    let header = format!("X: {}\r\n", "a".repeat(8000 - 5));
+    let mut head = request_line.to_string() + &header.repeat(8);
+    head += &"a".repeat(64 * 1024 - head.len());
+    let response = send(head);
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    assert!(response.contains("failed to accept request head"));
+}
+
+#[test]
+fn test_profile() {
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        Some(BreakpadInfo {
+            syms_dir: "tests/bpsyms".to_string(),
+        }),
+        None,
+    );
+
+    // Two threads with their own string arrays. The first has two frames in
+    // `main` and one in `f`, and a JS frame. The second has a frame in a
+    // library without symbols.
+    let mut profile = json!({
+        "meta": { "version": 27 },
+        "libs": [
+            { "debugName": "example-linux", "breakpadId": "BE4E976C325246EE9D6B7847A670B2A90" },
+            { "debugName": "libc.so.6", "breakpadId": "00000000000000000000000000000000F" },
+        ],
+        "threads": [
+            {
+                "resourceTable": { "lib": [0], "name": [0], "length": 1 },
+                "funcTable": {
+                    "name": [1, 2, 3, 4],
+                    "isJS": [false, false, false, true],
+                    "resource": [0, 0, 0, -1],
+                    "fileName": [null, null, null, 5],
+                    "lineNumber": [null, null, null, 10],
+                    "length": 4,
+                },
+                "frameTable": {
+                    "address": [0x1130, 0x1146, 0x1160, -1],
+                    "func": [0, 1, 2, 3],
+                    "line": [null, null, null, 11],
+                    "length": 4,
+                },
+                "stringArray": ["example-linux", "0x1130", "0x1146", "0x1160", "foo", "foo.js"],
+            },
+            {
+                "resourceTable": { "lib": [1], "name": [0], "length": 1 },
+                "funcTable": {
+                    "name": [1],
+                    "isJS": [false],
+                    "resource": [0],
+                    "fileName": [null],
+                    "lineNumber": [null],
+                    "length": 1,
+                },
+                "frameTable": { "address": [0x1234], "func": [0], "line": [null], "length": 1 },
+                "stringArray": ["libc.so.6", "0x1234"],
+            },
+        ],
+    });
+    fixer.symbolicate_profile(&mut profile).unwrap();
+
+    let file = "hg:hg.mozilla.org/integration/autoland:\
+                /home/njn/moz/fix-stacks/tests/example.c:94d31f914f29e72dd81a8880100d12f67e48a5b0";
+    assert_eq!(profile["meta"]["symbolicated"], true);
+    let thread = &profile["threads"][0];
+    assert_eq!(
+        thread["funcTable"],
+        json!({
+            "name": [1, 2, 3, 4, 6, 8],
+            "isJS": [false, false, false, true, false, false],
+            "resource": [0, 0, 0, -1, 0, 0],
+            "fileName": [null, null, null, 5, 7, 7],
+            "lineNumber": [null, null, null, 10, null, null],
+            "length": 6,
+        })
+    );
+    assert_eq!(thread["frameTable"]["func"], json!([4, 4, 5, 3]));
+    assert_eq!(thread["frameTable"]["line"], json!([24, 26, 16, 11]));
+    assert_eq!(
+        thread["stringArray"],
+        json!([
+            "example-linux",
+            "0x1130",
+            "0x1146",
+            "0x1160",
+            "foo",
+            "foo.js",
+            "main",
+            file,
+            "f"
+        ])
+    );
+    assert_eq!(profile["threads"][1]["funcTable"]["length"], 1);
+    assert_eq!(profile["threads"][1]["frameTable"]["func"], json!([0]));
+
+    // The JS frame isn't counted.
+    let stats = &fixer.stats;
+    assert_eq!(
+        (stats.frames, stats.fixed, stats.name_only, stats.unresolved),
+        (4, 3, 0, 1)
+    );
+
+    // A newer profile, with a shared string array.
+    let mut profile = json!({
+        "libs": [
+            { "debugName": "example-linux", "breakpadId": "BE4E976C325246EE9D6B7847A670B2A90" },
+        ],
+        "shared": { "stringArray": ["0x11b0"] },
+        "threads": [{
+            "resourceTable": { "lib": [0], "length": 1 },
+            "funcTable": { "name": [0], "resource": [0], "fileName": [null], "length": 1 },
+            "frameTable": { "address": [0x11b0], "func": [0], "length": 1 },
+        }],
+    });
+    fixer.symbolicate_profile(&mut profile).unwrap();
+    assert_eq!(
+        profile["shared"]["stringArray"],
+        json!(["0x11b0", "g", file])
+    );
+    assert_eq!(profile["threads"][0]["frameTable"]["func"], json!([1]));
+
+    // Bad profiles.
+    let mut bad = json!({ "libs": [], "threads": [{ "funcTable": {} }] });
+    let err = fixer.symbolicate_profile(&mut bad).unwrap_err();
+    assert_eq!(err.to_string(), "read `stringArray` in");
+    let mut bad = json!({ "threads": [] });
+    let err = fixer.symbolicate_profile(&mut bad).unwrap_err();
+    assert_eq!(err.to_string(), "read `libs` in");
+}
+
+#[test]
+fn test_dmd() {
+    // A DMD report, gzipped as DMD writes it. The frame descriptions contain
+    // characters that need escaping in JSON.
+    let report = json!({
+        "dmdEnvVar": "1",
+        "mode": "live",
+        "blockList": [{ "req": 16, "alloc": "A" }],
+        "traceTable": { "A": ["B", "C", "D", "E"] },
+        "frameTable": {
+            "B": "#00: ???[tests/example-linux +0x1130]",
+            "C": "#01: ???[tests/example-linux +0x1160]",
+            "D": "#02: foo\"bar[tests/example-linux +0x1]",
+            "E": "#00: ???[tests/example-linux +0x1130]",
+        },
+    });
+    let dir = TempDir::new("dmd");
+    let dmd_file = dir.join("dmd.json.gz");
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(report.to_string().as_bytes()).unwrap();
+    fs::write(&dmd_file, encoder.finish().unwrap()).unwrap();
+    let (mut report, format) = read_json_file(dmd_file.to_str().unwrap()).unwrap();
+
+    // The frames are fixed as plain text, even with `--json`.
+    let mut fixer = Fixer::new(JsonMode::Yes, None, None);
+    fixer.fix_dmd(&mut report).unwrap();
+    assert_eq!(
+        report["frameTable"],
+        json!({
+            "B": "#00: main (/home/njn/moz/fix-stacks/tests/example.c:24)",
+            "C": "#01: f (/home/njn/moz/fix-stacks/tests/example.c:16)",
+            "D": "#02: foo\"bar (tests/example-linux + 0x1)",
+            "E": "#00: main (/home/njn/moz/fix-stacks/tests/example.c:24)",
+        })
+    );
+    assert_eq!(report["traceTable"], json!({ "A": ["B", "C", "D", "E"] }));
+
+    // The fixed report is written back gzipped and minified, with its keys in
+    // the same order.
+    let mut output = vec![];
+    write_json(&mut output, &report, &format).unwrap();
+    let mut json = String::new();
+    flate2::read::GzDecoder::new(&output[..])
+        .read_to_string(&mut json)
+        .unwrap();
+    assert!(json.starts_with(r#"{"dmdEnvVar":"1","mode":"live","blockList":[{"#));
+    assert!(json.ends_with("}\n"));
+    assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), report);
+
+    let mut bad = json!({ "traceTable": {} });
+    let err = fixer.fix_dmd(&mut bad).unwrap_err();
+    assert_eq!(err.to_string(), "read `frameTable` in");
+}
+
+#[test]
+fn test_minidump_json() {
+    let mut fixer = Fixer::new(
+        JsonMode::No,
+        None,
+        Some(LocalFileInfo {
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
+        }),
+    );
+
+    // The output of `minidump-stackwalk --json`, trimmed. The first frame of
+    // each thread has no symbols.
+    let frames = json!([
+        { "frame": 0, "module": "example-linux", "module_offset": "0x1146", "trust": "context" },
+        {
+            "frame": 1,
+            "module": "example-linux",
+            "module_offset": "0x1160",
+            "function": "already_there",
+            "trust": "cfi",
+        },
+        { "frame": 2, "module": "libc.so.6", "module_offset": "0x2000", "trust": "cfi" },
+        { "frame": 3, "offset": "0x7f0000001000", "trust": "scan" },
+    ]);
+    let mut report = json!({
+        "crash_info": { "type": "SIGSEGV", "crashing_thread": 0 },
+        "crashing_thread": { "threads_index": 0, "frames": frames.clone() },
+        "modules": [
+            {
+                "filename": "example-linux",
+                "debug_file": "example-linux",
+                "debug_id": "BE4E976C325246EE9D6B7847A670B2A90",
+                "missing_symbols": true,
+            },
+            {
+                "filename": "libc.so.6",
+                "debug_file": "libc.so.6",
+                "debug_id": "00000000000000000000000000000000F",
+                "missing_symbols": true,
+            },
+        ],
+        "threads": [{ "frame_count": 4, "frames": frames }],
+    });
+    let mut expected = report.clone();
+    for pointer in ["/crashing_thread/frames/0", "/threads/0/frames/0"] {
+        let frame = expected.pointer_mut(pointer).unwrap();
+        frame["function"] = json!("main");
+        frame["function_offset"] = json!("0x16");
+        frame["file"] = json!("/home/njn/moz/fix-stacks/tests/example.c");
+        frame["line"] = json!(26);
+        frame["symbol_source"] = json!({ "kind": "native" });
+    }
+    fixer.fix_minidump_json(&mut report).unwrap();
+    assert_eq!(report, expected);
+
+    // Untouched fields are written back exactly as they were, in the same
+    // order, and pretty-printed or minified as the input was.
+    let input = r#"{
+  "status": "OK",
+  "modules": [
+    {
+      "filename": "example-linux",
+      "debug_id": "BE4E976C325246EE9D6B7847A670B2A90"
+    }
+  ],
+  "threads": [
+    {
+      "frames": [
+        {
+          "trust": "context",
+          "module_offset": "0x1146",
+          "module": "example-linux"
+        }
+      ],
+      "frame_count": 1
+    }
+  ]
+}
+"#;
+    let output = r#"{
+  "status": "OK",
+  "modules": [
+    {
+      "filename": "example-linux",
+      "debug_id": "BE4E976C325246EE9D6B7847A670B2A90"
+    }
+  ],
+  "threads": [
+    {
+      "frames": [
+        {
+          "trust": "context",
+          "module_offset": "0x1146",
+          "module": "example-linux",
+          "function": "main",
+          "function_offset": "0x16",
+          "symbol_source": {
+            "kind": "native"
+          },
+          "file": "/home/njn/moz/fix-stacks/tests/example.c",
+          "line": 26
+        }
+      ],
+      "frame_count": 1
+    }
+  ]
+}
+"#;
+    let dir = TempDir::new("minidump");
+    let report_file = dir.join("report.json");
+    let mut fix_file = |input: &str| {
+        fs::write(&report_file, input).unwrap();
+        let (mut report, format) = read_json_file(report_file.to_str().unwrap()).unwrap();
+        fixer.fix_minidump_json(&mut report).unwrap();
+        let mut output = vec![];
+        write_json(&mut output, &report, &format).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(fix_file(input), output);
+    let minify = |json: &str| {
+        let value: Value = serde_json::from_str(json).unwrap();
+        format!("{}\n", value)
+    };
+    assert_eq!(fix_file(&minify(input)), minify(output));
+}
+
+#[test]
+fn test_bytes() {
+    let mut fixer = Fixer::new(JsonMode::No, None, None);
+    let mut fix = |input: &[u8]| {
+        let mut output = vec![];
+        fix_stream(&mut fixer, input, &mut output).unwrap();
+        output
+    };
+
+    // Lines without frames are unchanged, whatever their bytes and endings.
+    let input = b"caf\xe9\r\n\xff\xfe\n\nno newline \xc3";
+    assert_eq!(fix(input), input);
+    // A line with invalid UTF-8 and a character used to represent invalid
+    // bytes is also unchanged.
+    let mut input = "#00: ???[\u{10ff41} +0x10]".as_bytes().to_vec();
+    input.extend_from_slice(b"\xff\n");
+    assert_eq!(fix(&input), input);
+
+    // Frames are fixed, with invalid UTF-8 around them preserved.
+    assert_eq!(
+        fix(b"\xe9 #00: ???[tests/example-linux +0x1130] \xff\xfe\r\n#01: ???[tests/example-linux +0x1160]"),
+        &b"\xe9 #00: main (/home/njn/moz/fix-stacks/tests/example.c:24) \xff\xfe\r\n\
+           #01: f (/home/njn/moz/fix-stacks/tests/example.c:16)"[..]
+    );
+
+    // A frame with an invalid file name can't be resolved, but is otherwise
+    // handled as usual.
+    assert_eq!(
+        fix(b"#00: ???[tests/\xe9 +0x10]\n"),
+        &b"#00: ??? (tests/\xe9 + 0x10)\n"[..]
+    );
+}
+
+#[test]
+fn test_no_panics() {
+    // Malformed frames are output unchanged.
+    let mut fixer = Fixer::new(JsonMode::Yes, None, None);
+    for line in [
+        "#00: ???[tests/example-linux +0x10000000000000000]",
+        "#00: ???[tests/\\x +0x1130]",
+        "#00: ???[tests/\\ud800 +0x1130]",
+        "    #00 pc 10000000000000000  tests/example-linux",
+    ] {
+        assert_eq!(fixer.fix(line.to_string()), line);
+    }
+
+    // Lines made of random sequences of fragments that resemble the
+    // recognized frame formats, to check that no line can cause a panic.
+    const FRAGMENTS: &[&str] = &[
+        "#",
+        "#00: ",
+        "#1234: ",
+        "#00 pc ",
+        "???",
+        "[",
+        "]",
+        " +0x",
+        "+0x",
+        "0x",
+        "1130",
+        "1160",
+        "36",
+        "ffffffffffffffff",
+        "10000000000000000",
+        "0",
+        " ",
+        "\t",
+        "\t    ",
+        "(",
+        ")",
+        " (",
+        "tests/example-linux",
+        "tests/example-linux-inline",
+        "tests/example-android.apk",
+        "tests/example-android.apk!/lib/x86_64/example-linux",
+        "tests/mac-multi",
+        "tests/bpsyms",
+        "tests",
+        "!/",
+        ".apk",
+        "/nonexistent/libxul.so",
+        "\\",
+        "\\u",
+        "\\ud800",
+        "\\n",
+        "\\\"",
+        "\"",
+        " (BuildId: ",
+        "be4e976c",
+        " (offset 0x",
+        "Module|",
+        "|",
+        "example-linux",
+        "BE4E976C325246EE9D6B7847A670B2A90",
+        "PERF_RECORD_MMAP2 ",
+        "1/1: [0x",
+        "7f0000001000",
+        "(0x",
+        ") @ ",
+        " fd:01 1 0]: r-xp ",
+        "example 1 1.0: ",
+        "hg:",
+        "git:",
+        "s3:",
+        ":",
+        "hg.mozilla.org/mozilla-central",
+        "94d31f914f29e72dd81a8880100d12f67e48a5b0",
+        "é",
+        "\u{0}",
+        "\u{10ff41}",
+        "\u{fffd}",
+    ];
+
+    // A xorshift generator, which is enough for this and needs no crate.
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next = move |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+    let mut lines = vec![];
+    for _ in 0..20_000 {
+        let len = 1 + next(12);
+        let line: String = (0..len).map(|_| FRAGMENTS[next(FRAGMENTS.len())]).collect();
+        lines.push(line);
+    }
+    let mut bytes = vec![];
+    for _ in 0..20_000 {
+        bytes.push(next(256) as u8);
+    }
+
+    let local_info = || {
+        Some(LocalFileInfo {
+            local_dirs: vec![PathBuf::from("tests")],
+            recursive: false,
+        })
+    };
+    let bp_info = || {
+        Some(BreakpadInfo {
+            syms_dir: "tests/bpsyms".to_string(),
+        })
+    };
+    let mut fixers = vec![
+        Fixer::new(JsonMode::No, None, None),
+        Fixer::new(JsonMode::Yes, None, local_info()),
+        Fixer::new(JsonMode::No, bp_info(), local_info()),
+        Fixer::new(JsonMode::Yes, bp_info(), None),
+    ];
+    let mut perf_fixer = Fixer::new(JsonMode::No, None, local_info());
+    perf_fixer.perf_info = Some(PerfInfo::new(vec![]));
+    fixers.push(perf_fixer);
+    let mut jit_fixer = Fixer::new(JsonMode::No, None, None);
+    jit_fixer.jit_info.jit_files = vec!["tests/example-linux".to_string()];
+    fixers.push(jit_fixer);
+    let mut source_link_fixer = Fixer::new(JsonMode::No, bp_info(), None);
+    source_link_fixer.source_link_info = Some(SourceLinkInfo::new("github", true));
+    fixers.push(source_link_fixer);
+
+    for fixer in &mut fixers {
+        for line in &lines {
+            fixer.fix(line.clone());
+        }
+        let mut output = vec![];
+        fix_stream(fixer, &bytes[..], &mut output).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of the stack frames in Android tombstones and debuggerd
+//! backtraces, which look like this:
+//!
+//! ```text
+//!     #00 pc 0000000000a1b2c3  /data/app/org.example/lib/arm64/libxul.so (Foo::bar()+12) (BuildId: 6c974ebe...)
+//! ```
+//!
+//! The function and build ID are optional, and there can be an
+//! `(offset 0x...)` annotation after the path for libraries loaded from an
+//! APK. The path can contain spaces, and there can be more text after the
+//! annotations.
+
+use regex::Regex;
+
+/// The parts of a tombstone frame. The line is the concatenation of
+/// `before`, `pc`, `sep`, `path`, `offset`, the function annotation (if
+/// present), `build_id_part` and `after`. The function annotation isn't
+/// recorded because the fixed frame replaces it.
+pub struct TombstoneFrame<'a> {
+    /// E.g. `    #00 pc `.
+    pub before: &'a str,
+
+    /// The address relative to the start of the library, as hex.
+    pub pc: &'a str,
+
+    /// The whitespace after `pc`.
+    pub sep: &'a str,
+
+    pub path: &'a str,
+
+    /// E.g. ` (offset 0x7000)`, or empty.
+    pub offset: &'a str,
+
+    /// E.g. ` (BuildId: 6c974ebe...)`, or empty.
+    pub build_id_part: &'a str,
+
+    /// The build ID within `build_id_part`.
+    pub build_id: Option<&'a str>,
+
+    /// Anything unrecognized at the end of the line.
+    pub after: &'a str,
+}
+
+pub struct TombstoneParser {
+    re: Regex,
+}
+
+impl TombstoneParser {
+    pub fn new() -> TombstoneParser {
+        TombstoneParser {
+            re: Regex::new(r"^(.*#\d+ pc )([0-9A-Fa-f]+)(\s+)(.*)$").unwrap(),
+        }
+    }
+
+    pub fn parse<'a>(&self, line: &'a str) -> Option<TombstoneFrame<'a>> {
+        let captures = self.re.captures(line)?;
+        let mut rest = captures.get(4).unwrap().as_str();
+
+        // The path can contain spaces, so it runs up to the first annotation
+        // or the end of the line.
+        let path_end = rest.find(" (").unwrap_or_else(|| rest.trim_end().len());
+        let path = &rest[..path_end];
+        if path.is_empty() {
+            return None;
+        }
+        rest = &rest[path_end..];
+
+        let mut offset = "";
+        if rest.starts_with(" (offset 0x") {
+            if let Some(index) = rest.find(')') {
+                offset = &rest[..index + 1];
+                rest = &rest[index + 1..];
+            }
+        }
+
+        // The function annotation, e.g. ` (Foo::bar()+12)`. The function name
+        // can contain parentheses, so the annotation ends at the matching
+        // one.
+        if rest.starts_with(" (") && !rest.starts_with(" (BuildId: ") {
+            let mut depth = 0;
+            let end = rest.char_indices().skip(1).find_map(|(i, c)| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    Some(i + 1)
+                } else {
+                    None
+                }
+            });
+            if let Some(end) = end {
+                rest = &rest[end..];
+            }
+        }
+
+        let mut build_id_part = "";
+        let mut build_id = None;
+        if let Some(id_rest) = rest.strip_prefix(" (BuildId: ") {
+            if let Some(index) = id_rest.find(')') {
+                let id = &id_rest[..index];
+                if !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+                    let len = " (BuildId: ".len() + index + 1;
+                    build_id_part = &rest[..len];
+                    build_id = Some(id);
+                    rest = &rest[len..];
+                }
+            }
+        }
+
+        Some(TombstoneFrame {
+            before: captures.get(1).unwrap().as_str(),
+            pc: captures.get(2).unwrap().as_str(),
+            sep: captures.get(3).unwrap().as_str(),
+            path,
+            offset,
+            build_id_part,
+            build_id,
+            after: rest,
+        })
+    }
+}
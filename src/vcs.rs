@@ -0,0 +1,190 @@
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Version control info within source file paths, as found in Firefox's
+//! Breakpad symbol files, and links to the source files it identifies.
+
+/// How the revision is written in the paths of a `VcsScheme`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RevKind {
+    /// A hex id, e.g. a Mercurial or git commit hash.
+    Hex,
+    /// Nothing, e.g. for files in S3 buckets, which are identified by a
+    /// digest within the path.
+    Empty,
+}
+
+/// A kind of version control annotated path, identified by its first
+/// colon-separated element.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VcsScheme {
+    name: String,
+    rev_kind: RevKind,
+}
+
+/// The built-in schemes. These cover the paths written by Mozilla's
+/// `dump_syms`, e.g.:
+/// - `hg:hg.mozilla.org/mozilla-central:dom/base/Element.cpp:<rev>`
+/// - `git:github.com/rust-lang/rust:library/core/src/panicking.rs:<rev>`, for
+///   the Rust standard library and other git repositories
+/// - `s3:gecko-generated-sources:<digest>/ipc/ipdl/PContent.cpp:`, for
+///   generated sources
+const VCS_SCHEMES: &[(&str, RevKind)] = &[
+    ("hg", RevKind::Hex),
+    ("git", RevKind::Hex),
+    ("s3", RevKind::Empty),
+];
+
+impl VcsScheme {
+    /// The built-in schemes.
+    pub fn builtin() -> Vec<VcsScheme> {
+        VCS_SCHEMES
+            .iter()
+            .map(|&(name, rev_kind)| VcsScheme {
+                name: name.to_string(),
+                rev_kind,
+            })
+            .collect()
+    }
+
+    /// Parse a scheme given with `--vcs-scheme`, which has the form `NAME`
+    /// (for a scheme with hex revisions) or `NAME:REV`, where `REV` is `hex`
+    /// or `none`.
+    pub fn from_spec(spec: &str) -> Result<VcsScheme, String> {
+        let (name, rev_kind) = match spec.split_once(':') {
+            None => (spec, RevKind::Hex),
+            Some((name, "hex")) => (name, RevKind::Hex),
+            Some((name, "none")) => (name, RevKind::Empty),
+            Some((_, rev)) => return Err(format!("bad revision kind `{}`.", rev)),
+        };
+        // A single letter would be confused with a Windows drive letter.
+        if name.len() < 2 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("bad VCS scheme name `{}`.", name));
+        }
+        Ok(VcsScheme {
+            name: name.to_string(),
+            rev_kind,
+        })
+    }
+
+    /// Is `rev` a valid revision for this scheme?
+    fn is_valid_rev(&self, rev: &str) -> bool {
+        match self.rev_kind {
+            RevKind::Hex => {
+                (7..=64).contains(&rev.len()) && rev.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            RevKind::Empty => rev.is_empty(),
+        }
+    }
+}
+
+/// A source file path annotated with version control info. E.g.
+/// `hg:hg.mozilla.org/integration/autoland:caps/BasePrincipal.cpp:04c31e994f29e72dd81a7340100d12f67e48a5b4`
+/// has a `vcs` of `hg`, a `repo` of `hg.mozilla.org/integration/autoland`, a
+/// `path` of `caps/BasePrincipal.cpp` and a `rev` of
+/// `04c31e994f29e72dd81a7340100d12f67e48a5b4`.
+#[derive(Debug, PartialEq)]
+pub struct VcsPath<'a> {
+    pub vcs: &'a str,
+    pub repo: &'a str,
+    pub path: &'a str,
+    pub rev: &'a str,
+}
+
+impl<'a> VcsPath<'a> {
+    /// Parse a version control annotated path of one of the given schemes.
+    ///
+    /// The scheme and repository never contain colons, and the revision is
+    /// after the last colon, so the path is everything in between. This
+    /// means paths containing colons, such as `c:\builds\foo.cpp`, are
+    /// handled correctly.
+    pub fn parse(file_name: &'a str, schemes: &[VcsScheme]) -> Option<VcsPath<'a>> {
+        let (vcs, rest) = file_name.split_once(':')?;
+        let scheme = schemes.iter().find(|scheme| scheme.name == vcs)?;
+
+        let (repo, rest) = rest.split_once(':')?;
+        let (path, rev) = rest.rsplit_once(':')?;
+        if repo.is_empty() || path.is_empty() || !scheme.is_valid_rev(rev) {
+            return None;
+        }
+
+        Some(VcsPath {
+            vcs,
+            repo,
+            path,
+            rev,
+        })
+    }
+}
+
+/// The named URL templates that can be used with `--source-url`.
+const URL_TEMPLATES: &[(&str, &str)] = &[
+    ("hg", "https://{repo}/file/{rev}/{path}#l{line}"),
+    (
+        "searchfox",
+        "https://searchfox.org/{repo_name}/rev/{rev}/{path}#{line}",
+    ),
+    (
+        "github",
+        "https://github.com/{repo_path}/blob/{rev}/{path}#L{line}",
+    ),
+];
+
+/// Info provided via the `--source-url` and `--osc8` flags.
+pub struct SourceLinkInfo {
+    /// A URL template. See `USAGE_MSG` for the placeholders it can contain.
+    template: String,
+
+    /// Should links be emitted as OSC 8 terminal hyperlinks, rather than as
+    /// plain URLs?
+    osc8: bool,
+}
+
+impl SourceLinkInfo {
+    /// `template` is either the name of one of `URL_TEMPLATES`, or a custom
+    /// template.
+    pub fn new(template: &str, osc8: bool) -> SourceLinkInfo {
+        let template = URL_TEMPLATES
+            .iter()
+            .find(|(name, _)| *name == template)
+            .map_or(template, |(_, template)| template);
+        SourceLinkInfo {
+            template: template.to_string(),
+            osc8,
+        }
+    }
+
+    /// Get the URL for a line within a file.
+    pub fn url(&self, vcs_path: &VcsPath, line: u64) -> String {
+        // E.g. `hg.mozilla.org` and `integration/autoland`.
+        let (host, repo_path) = vcs_path.repo.split_once('/').unwrap_or((vcs_path.repo, ""));
+        // E.g. `autoland`.
+        let repo_name = vcs_path.repo.rsplit('/').next().unwrap_or("");
+
+        self.template
+            .replace("{vcs}", vcs_path.vcs)
+            .replace("{repo}", vcs_path.repo)
+            .replace("{host}", host)
+            .replace("{repo_path}", repo_path)
+            .replace("{repo_name}", repo_name)
+            .replace("{rev}", vcs_path.rev)
+            .replace("{path}", vcs_path.path)
+            .replace("{line}", &line.to_string())
+    }
+
+    /// Get the text describing a line within a file: either the URL itself,
+    /// or `path:line` hyperlinked to the URL.
+    pub fn location(&self, vcs_path: &VcsPath, line: u64) -> String {
+        let url = self.url(vcs_path, line);
+        if self.osc8 {
+            format!(
+                "\x1b]8;;{}\x1b\\{}:{}\x1b]8;;\x1b\\",
+                url, vcs_path.path, line
+            )
+        } else {
+            url
+        }
+    }
+}
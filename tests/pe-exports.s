@@ -0,0 +1,38 @@
+# Two exported functions with an unexported one between them. The SEH
+# directives cause `.pdata` entries to be emitted for all three.
+        .text
+
+        .globl  exported_A
+        .def    exported_A; .scl 2; .type 32; .endef
+        .seh_proc exported_A
+exported_A:
+        pushq   %rbp
+        .seh_pushreg %rbp
+        .seh_endprologue
+        movl    $1, %eax
+        popq    %rbp
+        retq
+        .seh_endproc
+
+        .def    internal; .scl 3; .type 32; .endef
+        .seh_proc internal
+internal:
+        pushq   %rbp
+        .seh_pushreg %rbp
+        .seh_endprologue
+        movl    $2, %eax
+        popq    %rbp
+        retq
+        .seh_endproc
+
+        .globl  exported_B
+        .def    exported_B; .scl 2; .type 32; .endef
+        .seh_proc exported_B
+exported_B:
+        pushq   %rbp
+        .seh_pushreg %rbp
+        .seh_endprologue
+        movl    $3, %eax
+        popq    %rbp
+        retq
+        .seh_endproc